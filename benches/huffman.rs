@@ -0,0 +1,179 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use ul31codec::huffman;
+
+/**
+ * The Huffman coder before the table decoder, kept as the baseline of the benchmarks.
+ * It builds the tree by repeated minimum search, finds every code by searching the tree
+ * and decodes by walking the tree one bit at a time from a clone of the root.
+*/
+mod tree {
+    use std::rc::Rc;
+    use ul31codec::bitstream::{BitStreamReader, BitStreamWriter};
+
+    #[derive(Clone, Default, Debug)]
+    struct HuffmanNode {
+        byte: u8,
+        value: usize,
+        left: Option<Rc<HuffmanNode>>,
+        right: Option<Rc<HuffmanNode>>,
+    }
+
+    impl HuffmanNode {
+        fn build(freq_list: &[usize]) -> Self {
+            fn find_min(nodes: &mut Vec<HuffmanNode>) -> HuffmanNode {
+                let mut min_pos = 0;
+                for (i, node) in nodes.iter().enumerate() {
+                    if node.value < nodes[min_pos].value {
+                        min_pos = i;
+                    }
+                }
+                nodes.remove(min_pos)
+            }
+
+            let mut nodes: Vec<_> = freq_list
+                .iter()
+                .enumerate()
+                .map(|(byte, freq)| HuffmanNode {
+                    byte: byte as u8,
+                    value: *freq,
+                    ..Default::default()
+                })
+                .collect();
+            while nodes.len() > 1 {
+                let min1 = find_min(&mut nodes);
+                let min2 = find_min(&mut nodes);
+                nodes.push(HuffmanNode {
+                    value: min1.value + min2.value,
+                    left: Some(Rc::new(min1)),
+                    right: Some(Rc::new(min2)),
+                    ..Default::default()
+                });
+            }
+            nodes.remove(0)
+        }
+        fn get_huffman_code(&self, byte: u8) -> Vec<u8> {
+            fn search(root: &HuffmanNode, byte: u8, bits: &mut Vec<u8>) -> bool {
+                if root.is_leaf() {
+                    return root.byte == byte;
+                }
+                for (bit, child) in [(0, &root.left), (1, &root.right)] {
+                    if search(&child.clone().unwrap(), byte, bits) {
+                        bits.push(bit);
+                        return true;
+                    }
+                }
+                false
+            }
+
+            let mut bits = Vec::new();
+            search(self, byte, &mut bits);
+            bits.reverse();
+            bits
+        }
+        fn get_byte(&self, bits: &mut BitStreamReader) -> u8 {
+            let mut root = Rc::new(self.clone());
+            while !root.is_leaf() {
+                root = if bits.read().unwrap() == 0 {
+                    root.left.clone().unwrap()
+                } else {
+                    root.right.clone().unwrap()
+                };
+            }
+            root.byte
+        }
+        fn load_from_bits(bits: &mut BitStreamReader, words: &mut Vec<u8>) -> Self {
+            let mut root = Self::default();
+            if bits.read().unwrap() == 0 {
+                root.left = Some(Rc::new(Self::load_from_bits(bits, words)));
+                root.right = Some(Rc::new(Self::load_from_bits(bits, words)));
+            } else {
+                root.byte = words.remove(0);
+            }
+            root
+        }
+        fn dump_to_bits(&self, bits: &mut BitStreamWriter, words: &mut Vec<u8>) {
+            if self.is_leaf() {
+                bits.write(1);
+                words.push(self.byte);
+            } else {
+                bits.write(0);
+                self.left.clone().unwrap().dump_to_bits(bits, words);
+                self.right.clone().unwrap().dump_to_bits(bits, words);
+            }
+        }
+        fn is_leaf(&self) -> bool {
+            self.left.is_none() && self.right.is_none()
+        }
+    }
+
+    /** number of bytes, the 256 leaf bytes, then the tree and the codes in one bit stream */
+    pub fn encode(bytes: &[u8]) -> Vec<u8> {
+        let mut freq_list = [0; 256];
+        for byte in bytes {
+            freq_list[*byte as usize] += 1;
+        }
+        let root = HuffmanNode::build(&freq_list);
+        let dict: Vec<_> = (0..=255).map(|byte| root.get_huffman_code(byte)).collect();
+
+        let mut bits = BitStreamWriter::default();
+        let mut words = Vec::new();
+        root.dump_to_bits(&mut bits, &mut words);
+        for byte in bytes {
+            for bit in &dict[*byte as usize] {
+                bits.write(*bit);
+            }
+        }
+
+        let mut data = Vec::new();
+        data.extend((bytes.len() as u32).to_be_bytes());
+        data.extend(words);
+        data.extend(bits.data);
+        data
+    }
+
+    pub fn decode(bytes: &[u8]) -> Vec<u8> {
+        let size = u32::from_be_bytes(bytes[0..4].try_into().unwrap()) as usize;
+        let mut words = bytes[4..4 + 256].to_vec();
+        let mut bits = BitStreamReader::from_bytes(&bytes[4 + 256..]);
+        let root = HuffmanNode::load_from_bits(&mut bits, &mut words);
+        (0..size).map(|_| root.get_byte(&mut bits)).collect()
+    }
+}
+
+/** bytes skewed like coefficient symbols, small values are the most common */
+fn skewed(len: usize) -> Vec<u8> {
+    let mut seed = 0x31c0_dec0_u64;
+    (0..len)
+        .map(|_| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let r = (seed >> 33) % 1000;
+            (r * r / 4000) as u8
+        })
+        .collect()
+}
+
+fn bench(c: &mut Criterion) {
+    let bytes = skewed(1 << 20);
+    let encoded = huffman::encode(&bytes);
+    let tree_encoded = tree::encode(&bytes);
+    assert_eq!(tree::decode(&tree_encoded), bytes);
+
+    c.bench_function("encode", |b| b.iter(|| huffman::encode(black_box(&bytes))));
+    c.bench_function("encode tree", |b| {
+        b.iter(|| tree::encode(black_box(&bytes)))
+    });
+    c.bench_function("decode table", |b| {
+        b.iter(|| huffman::decode(black_box(&encoded)).unwrap())
+    });
+    c.bench_function("decode bitwise", |b| {
+        b.iter(|| huffman::decode_bitwise(black_box(&encoded)).unwrap())
+    });
+    c.bench_function("decode tree", |b| {
+        b.iter(|| tree::decode(black_box(&tree_encoded)))
+    });
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
@@ -1,3 +1,5 @@
+use crate::error::DecodeError;
+
 #[derive(Default)]
 pub struct BitStreamWriter {
     pub data: Vec<u8>,
@@ -19,8 +21,15 @@ impl BitStreamWriter {
             self.byte_ptr += 1;
         }
     }
-    pub fn total_bits(&self) -> usize {
-        8 * self.byte_ptr + self.bit_ptr
+    /** number of bits written */
+    pub fn bit_len(&self) -> usize {
+        self.byte_ptr * 8 + self.bit_ptr
+    }
+    /** write the lowest `count` bits of `value`, most significant first */
+    pub fn write_bits(&mut self, value: u32, count: usize) {
+        for i in (0..count).rev() {
+            self.write((value >> i) as u8 & 1);
+        }
     }
 }
 
@@ -38,24 +47,96 @@ impl<'a> BitStreamReader<'a> {
             ..Default::default()
         }
     }
-    pub fn read(&mut self) -> u8 {
-        let bit = (self.data[self.byte_ptr] >> (7 - self.bit_ptr)) & 1;
+    pub fn read(&mut self) -> Result<u8, DecodeError> {
+        let byte = self
+            .data
+            .get(self.byte_ptr)
+            .ok_or(DecodeError::DataOverrun)?;
+        let bit = (byte >> (7 - self.bit_ptr)) & 1;
         self.bit_ptr += 1;
         if self.bit_ptr == 8 {
             self.bit_ptr = 0;
             self.byte_ptr += 1;
         }
 
-        bit
+        Ok(bit)
+    }
+    /** number of bits left to read */
+    pub fn remaining(&self) -> usize {
+        (self.data.len() * 8).saturating_sub(self.byte_ptr * 8 + self.bit_ptr)
+    }
+    /** next `count` bits (up to 32) without consuming them, zeros past the end of the data */
+    pub fn peek_bits(&self, count: usize) -> u32 {
+        let mut window = 0u64;
+        for i in 0..5 {
+            let byte = self.data.get(self.byte_ptr + i).copied().unwrap_or(0);
+            window = (window << 8) | byte as u64;
+        }
+        ((window >> (40 - self.bit_ptr - count)) & ((1 << count) - 1)) as u32
+    }
+    pub fn skip(&mut self, count: usize) -> Result<(), DecodeError> {
+        if count > self.remaining() {
+            return Err(DecodeError::DataOverrun);
+        }
+        self.bit_ptr += count;
+        self.byte_ptr += self.bit_ptr / 8;
+        self.bit_ptr %= 8;
+        Ok(())
     }
-    pub fn total_bytes(&self) -> usize {
+    /** skip to the start of the next byte unless already there */
+    pub fn align(&mut self) {
         if self.bit_ptr > 0 {
-            self.byte_ptr + 1
-        } else {
-            self.byte_ptr
+            self.bit_ptr = 0;
+            self.byte_ptr += 1;
         }
     }
-    pub fn total_bits(&self) -> usize {
-        8 * self.byte_ptr + self.bit_ptr
+    /** read `count` bits, most significant first */
+    pub fn read_bits(&mut self, count: usize) -> Result<u32, DecodeError> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = (value << 1) | self.read()? as u32;
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut writer = BitStreamWriter::default();
+        writer.write(1);
+        writer.write_bits(0x2a, 7);
+        writer.write_bits(0xdead_beef, 32);
+        assert_eq!(writer.bit_len(), 40);
+
+        let mut reader = BitStreamReader::from_bytes(&writer.data);
+        assert_eq!(reader.read(), Ok(1));
+        assert_eq!(reader.peek_bits(7), 0x2a);
+        assert_eq!(reader.read_bits(7), Ok(0x2a));
+        assert_eq!(reader.read_bits(32), Ok(0xdead_beef));
+        assert_eq!(reader.remaining(), 0);
+    }
+
+    #[test]
+    fn read_past_end() {
+        let data = [0xa5, 0x0f];
+        let mut reader = BitStreamReader::from_bytes(&data);
+        assert_eq!(reader.read_bits(12), Ok(0xa50));
+        /* peeking pads with zeros, reading does not */
+        assert_eq!(reader.peek_bits(8), 0xf0);
+        assert_eq!(reader.read_bits(8), Err(DecodeError::DataOverrun));
+
+        let mut reader = BitStreamReader::from_bytes(&data);
+        assert_eq!(reader.skip(17), Err(DecodeError::DataOverrun));
+        assert_eq!(reader.skip(16), Ok(()));
+        assert_eq!(reader.read(), Err(DecodeError::DataOverrun));
+
+        let mut reader = BitStreamReader::from_bytes(&[]);
+        assert_eq!(reader.read(), Err(DecodeError::DataOverrun));
+        reader.align();
+        assert_eq!(reader.remaining(), 0);
     }
 }
@@ -0,0 +1,262 @@
+use crate::bitstream::{BitStreamReader, BitStreamWriter};
+use crate::error::DecodeError;
+
+/** precision of a probability */
+const PROB_BITS: u32 = 11;
+/** how fast probabilities follow the coded bins, a larger shift adapts slower */
+const ADAPT_SHIFT: u32 = 4;
+/** the range is renormalized by a byte whenever it drops below this */
+const TOP: u32 = 1 << 24;
+
+/** adaptive probability of a bin being 0 */
+#[derive(Clone, Copy, Debug)]
+pub struct Context {
+    prob: u16,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self {
+            prob: 1 << (PROB_BITS - 1),
+        }
+    }
+}
+
+impl Context {
+    fn update(&mut self, bin: u8) {
+        if bin == 0 {
+            self.prob += ((1 << PROB_BITS) - self.prob) >> ADAPT_SHIFT;
+        } else {
+            self.prob -= self.prob >> ADAPT_SHIFT;
+        }
+    }
+}
+
+/**
+ * Binary arithmetic encoder.
+ * Every bin splits the range by the probability of its context, which then adapts towards the bin,
+ * so a well predicted bin costs a fraction of a bit.
+*/
+pub struct ArithmeticWriter<'a> {
+    bits: &'a mut BitStreamWriter,
+    low: u64,
+    range: u32,
+    /** last byte not written yet since a carry may still reach it, and the 0xff bytes after it */
+    cache: u8,
+    cache_size: usize,
+}
+
+impl<'a> ArithmeticWriter<'a> {
+    pub fn new(bits: &'a mut BitStreamWriter) -> Self {
+        Self {
+            bits,
+            low: 0,
+            range: u32::MAX,
+            cache: 0,
+            cache_size: 1,
+        }
+    }
+    pub fn encode_bin(&mut self, context: &mut Context, bin: u8) {
+        let bound = (self.range >> PROB_BITS) * context.prob as u32;
+        if bin == 0 {
+            self.range = bound;
+        } else {
+            self.low += bound as u64;
+            self.range -= bound;
+        }
+        context.update(bin);
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+    fn shift_low(&mut self) {
+        if self.low < 0xff00_0000 || self.low >> 32 != 0 {
+            let carry = (self.low >> 32) as u8;
+            let mut byte = self.cache;
+            while self.cache_size > 0 {
+                self.bits.write_bits(byte.wrapping_add(carry) as u32, 8);
+                byte = 0xff;
+                self.cache_size -= 1;
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.cache_size += 1;
+        self.low = (self.low & 0x00ff_ffff) << 8;
+    }
+    /** write out the rest of the range, the reader consumes exactly the bytes written */
+    pub fn finish(mut self) {
+        for _ in 0..5 {
+            self.shift_low();
+        }
+    }
+}
+
+pub struct ArithmeticReader<'a, 'b> {
+    bits: &'a mut BitStreamReader<'b>,
+    code: u32,
+    range: u32,
+}
+
+impl<'a, 'b> ArithmeticReader<'a, 'b> {
+    pub fn new(bits: &'a mut BitStreamReader<'b>) -> Result<Self, DecodeError> {
+        let mut code = 0;
+        for _ in 0..5 {
+            code = (code << 8) | bits.read_bits(8)?;
+        }
+        Ok(Self {
+            bits,
+            code,
+            range: u32::MAX,
+        })
+    }
+    pub fn decode_bin(&mut self, context: &mut Context) -> Result<u8, DecodeError> {
+        let bound = (self.range >> PROB_BITS) * context.prob as u32;
+        let bin = if self.code < bound {
+            self.range = bound;
+            0
+        } else {
+            self.code -= bound;
+            self.range -= bound;
+            1
+        };
+        context.update(bin);
+        while self.range < TOP {
+            self.range <<= 8;
+            self.code = (self.code << 8) | self.bits.read_bits(8)?;
+        }
+        Ok(bin)
+    }
+}
+
+/**
+ * Contexts of byte symbols.
+ * A byte is coded as 8 bins from the most significant one, each with a context
+ * of the bins before it. The tree of contexts is picked by the class of the previous symbol,
+ * since the syntax elements of the coefficient layer follow each other in patterns:
+ * 0 ends a block or is a zero flag, 1 is a small level or a set flag,
+ * other levels without and with a run of zeros before them.
+*/
+struct SymbolContexts {
+    trees: [[Context; 256]; 4],
+    previous: u8,
+}
+
+impl Default for SymbolContexts {
+    fn default() -> Self {
+        Self {
+            trees: [[Context::default(); 256]; 4],
+            previous: 0,
+        }
+    }
+}
+
+impl SymbolContexts {
+    fn tree(&mut self) -> &mut [Context; 256] {
+        let class = match self.previous {
+            0 => 0,
+            1 => 1,
+            2..=15 => 2,
+            _ => 3,
+        };
+        &mut self.trees[class]
+    }
+    fn encode(&mut self, writer: &mut ArithmeticWriter, symbol: u8) {
+        let tree = self.tree();
+        let mut node = 1;
+        for i in (0..8).rev() {
+            let bin = (symbol >> i) & 1;
+            writer.encode_bin(&mut tree[node], bin);
+            node = node * 2 + bin as usize;
+        }
+        self.previous = symbol;
+    }
+    fn decode(&mut self, reader: &mut ArithmeticReader) -> Result<u8, DecodeError> {
+        let tree = self.tree();
+        let mut node = 1;
+        for _ in 0..8 {
+            node = node * 2 + reader.decode_bin(&mut tree[node])? as usize;
+        }
+        self.previous = node as u8;
+        Ok(self.previous)
+    }
+}
+
+/**
+ * Compress bytes with the adaptive arithmetic coder.
+ *
+ * # Data structure
+ * |Start|End|Field|
+ * |-----|---|-----|
+ * |0    |4  |Number of bytes|
+ * |4    |   |Arithmetic coded bins|
+*/
+pub fn encode(bytes: &[u8]) -> Vec<u8> {
+    let mut bits = BitStreamWriter::default();
+    encode_into(bytes, &mut bits);
+    bits.data
+}
+
+/** append the coded bytes to `bits`, laid out like `encode` does */
+pub fn encode_into(bytes: &[u8], bits: &mut BitStreamWriter) {
+    bits.write_bits(bytes.len() as u32, 32);
+    let mut writer = ArithmeticWriter::new(bits);
+    let mut contexts = SymbolContexts::default();
+    for byte in bytes {
+        contexts.encode(&mut writer, *byte);
+    }
+    writer.finish();
+}
+
+/** decode a stream of any size up to the 32-bit limit of its count */
+pub fn decode(bytes: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    decode_from(&mut BitStreamReader::from_bytes(bytes), usize::MAX)
+}
+
+/** read at most `max_size` bytes written by `encode_into`, leaving `bits` right after them */
+pub fn decode_from(bits: &mut BitStreamReader, max_size: usize) -> Result<Vec<u8>, DecodeError> {
+    let size = bits.read_bits(32)? as usize;
+    if size > max_size {
+        return Err(DecodeError::DataOverrun);
+    }
+    /* bytes can cost a tiny fraction of a bit, so only `max_size` bounds the output */
+    let mut data = Vec::with_capacity(size.min(bits.remaining()));
+    let mut reader = ArithmeticReader::new(bits)?;
+    let mut contexts = SymbolContexts::default();
+
+    for _ in 0..size {
+        data.push(contexts.decode(&mut reader)?);
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entropy::tests::symbols;
+
+    #[test]
+    fn round_trip() {
+        for len in [0, 1, 2, 100, 5000] {
+            let bytes = symbols(len);
+            assert_eq!(decode(&encode(&bytes)), Ok(bytes));
+        }
+        /* long runs of one byte drive the contexts to their most skewed probabilities */
+        let bytes: Vec<u8> = (0..20000)
+            .map(|i| if i % 5000 == 0 { 255 } else { 0 })
+            .collect();
+        assert_eq!(decode(&encode(&bytes)), Ok(bytes));
+    }
+
+    #[test]
+    fn size_beyond_max() {
+        let mut coded = encode(&[0; 1000]);
+        let mut bits = BitStreamReader::from_bytes(&coded);
+        assert_eq!(decode_from(&mut bits, 999), Err(DecodeError::DataOverrun));
+        /* a long run of one byte costs a fraction of a bit each, a few bytes cover a huge count */
+        coded[0..4].copy_from_slice(&u32::MAX.to_be_bytes());
+        let mut bits = BitStreamReader::from_bytes(&coded);
+        assert_eq!(decode_from(&mut bits, 1000), Err(DecodeError::DataOverrun));
+    }
+}
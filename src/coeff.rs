@@ -0,0 +1,198 @@
+use crate::bitstream::{BitStreamReader, BitStreamWriter};
+use crate::entropy::{EntropyBackend, EntropyCoder, SymbolStats};
+use crate::error::DecodeError;
+use crate::mat::Matrix;
+
+/** end of block, all remaining coefficients are zero */
+const EOB: u8 = 0x00;
+/** run of 16 zeros */
+const ZRL: u8 = 0xf0;
+
+/** zig-zag scan order of a `n`x`n` block as `(x, y)` positions */
+pub fn zigzag(n: usize) -> Vec<(usize, usize)> {
+    let mut order = Vec::with_capacity(n * n);
+    for s in 0..2 * n - 1 {
+        let min = s.saturating_sub(n - 1);
+        let max = s.min(n - 1);
+        if s % 2 == 0 {
+            for x in min..=max {
+                order.push((x, s - x));
+            }
+        } else {
+            for x in (min..=max).rev() {
+                order.push((x, s - x));
+            }
+        }
+    }
+    order
+}
+
+/** number of bits needed to store the magnitude of `level` */
+fn level_size(level: i16) -> usize {
+    (16 - level.unsigned_abs().leading_zeros()) as usize
+}
+
+/** symbols of a block, each with the level bits following it as `(bits, size)` */
+fn block_symbols(block: &Matrix<i16>) -> Vec<(u8, u32, usize)> {
+    let mut symbols = Vec::new();
+    let mut run = 0usize;
+    for (x, y) in zigzag(block.len()) {
+        let level = block.get(x, y);
+        if level == 0 {
+            run += 1;
+            continue;
+        }
+        while run > 15 {
+            symbols.push((ZRL, 0, 0));
+            run -= 16;
+        }
+        let size = level_size(level);
+        /* negative levels are stored in one's complement like JPEG */
+        let bits = if level > 0 {
+            level as i32
+        } else {
+            level as i32 + (1 << size) - 1
+        };
+        symbols.push((((run << 4) | size) as u8, bits as u32, size));
+        run = 0;
+    }
+    if run > 0 {
+        symbols.push((EOB, 0, 0));
+    }
+    symbols
+}
+
+/**
+ * Coefficient entropy coder.
+ *
+ * Every non-zero coefficient in zig-zag order is coded as a symbol byte
+ * `run << 4 | size` followed by `size` raw bits of its level, as JPEG does.
+ * Other syntax elements are single symbols in between.
+ * Symbols are compressed with the chosen entropy backend, level bits are stored as is.
+ *
+ * # Data structure
+ * |Start|End|Field|
+ * |-----|---|-----|
+ * |0    |   |Coded symbols, padded to a whole byte|
+ * |     |   |Level bits|
+*/
+pub struct CoeffWriter {
+    coder: &'static dyn EntropyCoder,
+    symbols: Vec<u8>,
+    bits: BitStreamWriter,
+    stats: SymbolStats,
+}
+
+impl Default for CoeffWriter {
+    fn default() -> Self {
+        Self::new(EntropyBackend::default())
+    }
+}
+
+impl CoeffWriter {
+    pub fn new(backend: EntropyBackend) -> Self {
+        Self {
+            coder: backend.coder(),
+            symbols: Vec::new(),
+            bits: BitStreamWriter::default(),
+            stats: SymbolStats::default(),
+        }
+    }
+    pub fn write_block(&mut self, block: &Matrix<i16>) {
+        for (symbol, bits, size) in block_symbols(block) {
+            self.write_symbol(symbol);
+            self.bits.write_bits(bits, size);
+        }
+    }
+    /** write a syntax element other than coefficients */
+    pub fn write_symbol(&mut self, symbol: u8) {
+        self.symbols.push(symbol);
+        self.stats.add(symbol);
+    }
+    /** estimated length of a symbol in the chosen backend, from the symbols written so far */
+    pub fn symbol_bits(&self, symbol: u8) -> f64 {
+        self.coder.estimate_bits(&self.stats, symbol)
+    }
+    /** estimated bits of a non-zero `level` after `run` zeros */
+    pub fn level_bits(&self, run: usize, level: i16) -> f64 {
+        let size = level_size(level);
+        let symbol = (((run % 16) << 4) | size) as u8;
+        (run / 16) as f64 * self.symbol_bits(ZRL) + self.symbol_bits(symbol) + size as f64
+    }
+    pub fn eob_bits(&self) -> f64 {
+        self.symbol_bits(EOB)
+    }
+    /** estimated bits of a block, used by the encoder to compare coding choices */
+    pub fn estimate_bits(&self, block: &Matrix<i16>) -> f64 {
+        block_symbols(block)
+            .iter()
+            .map(|(symbol, _, size)| self.symbol_bits(*symbol) + *size as f64)
+            .sum()
+    }
+    pub fn finish(self) -> Vec<u8> {
+        let mut bits = BitStreamWriter::default();
+        self.coder.encode(&self.symbols, &mut bits);
+        /* level bits start at the next whole byte */
+        let mut data = bits.data;
+        data.extend(self.bits.data);
+        data
+    }
+}
+
+pub struct CoeffReader<'a> {
+    symbols: std::vec::IntoIter<u8>,
+    bits: BitStreamReader<'a>,
+}
+
+impl<'a> CoeffReader<'a> {
+    /** decode the symbols of `bytes`, rejecting more than `max_symbols` of them */
+    pub fn new(
+        bytes: &'a [u8],
+        backend: EntropyBackend,
+        max_symbols: usize,
+    ) -> Result<Self, DecodeError> {
+        let mut bits = BitStreamReader::from_bytes(bytes);
+        let symbols = backend.coder().decode(&mut bits, max_symbols)?;
+        bits.align();
+        Ok(Self {
+            symbols: symbols.into_iter(),
+            bits,
+        })
+    }
+    /** number of symbols not read yet */
+    pub fn symbols_left(&self) -> usize {
+        self.symbols.len()
+    }
+    pub fn read_symbol(&mut self) -> Result<u8, DecodeError> {
+        self.symbols.next().ok_or(DecodeError::DataOverrun)
+    }
+    pub fn read_block(&mut self, n: usize) -> Result<Matrix<i16>, DecodeError> {
+        let mut block = Matrix::new(n);
+        let order = zigzag(n);
+        let mut pos = 0;
+        while pos < order.len() {
+            let symbol = self.read_symbol()?;
+            if symbol == EOB {
+                break;
+            }
+            pos += (symbol >> 4) as usize;
+            let size = (symbol & 0xf) as usize;
+            if size == 0 {
+                /* ZRL */
+                pos += 1;
+                continue;
+            }
+            let bits = self.bits.read_bits(size)? as i32;
+            let level = if bits >> (size - 1) == 1 {
+                bits
+            } else {
+                bits - (1 << size) + 1
+            };
+            /* a run of zeros past the end of the block */
+            let (x, y) = *order.get(pos).ok_or(DecodeError::InvalidData)?;
+            block.set(x, y, level as i16);
+            pos += 1;
+        }
+        Ok(block)
+    }
+}
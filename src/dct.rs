@@ -1,81 +1,344 @@
+use crate::coeff::{self, CoeffReader, CoeffWriter};
+use crate::deblock::{TailKind, TailMap};
+use crate::error::DecodeError;
+use crate::intra::{self, IntraMode};
 use crate::mat::Matrix;
 use std::f64::consts::PI;
-use yuv::YUVFrame;
+use std::sync::OnceLock;
+use yuv::{YUV420Frame, YUVFrame};
 
+/** size of the quantization tables */
 pub const TAIL_SIZE: usize = 8;
 
-fn q_mat() -> Matrix<f64> {
+/** smallest and largest transform sizes, tails are chosen per region between them */
+pub const MIN_TAIL_SIZE: usize = 4;
+pub const MAX_TAIL_SIZE: usize = 32;
+
+/** factor between integer transform coefficients and quantizer input */
+const INT_SCALE: i32 = 16;
+
+/**
+ * HEVC transform coefficients, about `64 * sqrt(2) * cos(m * PI / 64)`.
+ * All integer transform matrices from 4 to 32 points are built from them.
+*/
+const INT_COS: [i32; 33] = [
+    64, 90, 90, 90, 89, 88, 87, 85, 83, 82, 80, 78, 75, 73, 70, 67, 64, 61, 57, 54, 50, 46, 43, 38,
+    36, 31, 25, 22, 18, 13, 9, 4, 0,
+];
+
+/** index of a tail size in tables holding one entry per size */
+fn size_index(n: usize) -> usize {
+    (n / MIN_TAIL_SIZE).trailing_zeros() as usize
+}
+
+/** all tail sizes from the smallest to the largest */
+fn tail_sizes() -> impl Iterator<Item = usize> {
+    (0..=size_index(MAX_TAIL_SIZE)).map(|i| MIN_TAIL_SIZE << i)
+}
+
+/** integer basis laid out like `cos_table()` */
+fn int_table(n: usize) -> &'static Matrix<i32> {
+    static TABLES: [OnceLock<Matrix<i32>>; 4] = [const { OnceLock::new() }; 4];
+    TABLES[size_index(n)].get_or_init(|| {
+        let mut table = Matrix::new(n);
+        for i in 0..n {
+            for u in 0..n {
+                /* the basis of an n-point transform is every (32 / n)th row of the 32-point one */
+                let m = (u * MAX_TAIL_SIZE / n * (2 * i + 1)) % 128;
+                let e = match m {
+                    0..=32 => INT_COS[m],
+                    33..=64 => -INT_COS[64 - m],
+                    65..=96 => -INT_COS[m - 64],
+                    _ => INT_COS[128 - m],
+                };
+                table.set(i, u, e);
+            }
+        }
+        table
+    })
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transform {
+    /** floating point DCT-II, result may vary between platforms */
+    #[default]
+    Float,
+    /** integer approximated DCT, bit-exact on every platform */
+    Integer,
+}
+
+/** how a block is predicted, the dead-zone quantizer rounds them differently */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockKind {
+    Intra,
+    Inter,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Quantizer {
+    /** round every coefficient to the nearest level */
+    #[default]
+    Round,
+    /**
+     * Round magnitudes down unless their fraction reaches `1 - offset`,
+     * leaving a dead zone around zero that is wider than the other intervals.
+     * Offsets are fractions of the quantizer step for intra and inter blocks.
+     * With `adaptive`, the encoder keeps moving the offsets so that the coefficients
+     * falling into a level are centered on its reconstruction.
+     */
+    DeadZone {
+        intra: f64,
+        inter: f64,
+        adaptive: bool,
+    },
+    /**
+     * rate-distortion optimized quantization, levels are lowered or zeroed
+     * when the bits saved are worth more than the added distortion.
+     * `lambda` is the cost of one bit in squared quantizer steps, about 0.1 is a good start.
+     */
+    Rdo { lambda: f64 },
+}
+
+impl Quantizer {
+    /** dead-zone quantizer with the usual offsets of 1/3 for intra and 1/6 for inter blocks */
+    pub fn dead_zone() -> Self {
+        Self::DeadZone {
+            intra: 1. / 3.,
+            inter: 1. / 6.,
+            adaptive: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Plane {
+    Y,
+    U,
+    V,
+}
+
+impl Plane {
+    /** resolution of the plane, chroma planes are subsampled by 2 in both directions */
+    pub(crate) fn resolution<T: YUVFrame>(self, frame: &T) -> (usize, usize) {
+        let (width, height) = frame.get_resolution();
+        match self {
+            Plane::Y => (width, height),
+            Plane::U | Plane::V => (width.div_ceil(2), height.div_ceil(2)),
+        }
+    }
+    pub(crate) fn get_pixel<T: YUVFrame>(self, frame: &T, x: usize, y: usize) -> u8 {
+        match self {
+            Plane::Y => frame.get_pixel_y(x, y),
+            Plane::U => frame.get_pixel_u(2 * x, 2 * y),
+            Plane::V => frame.get_pixel_v(2 * x, 2 * y),
+        }
+    }
+    pub(crate) fn set_pixel<T: YUVFrame>(self, frame: &mut T, x: usize, y: usize, value: u8) {
+        match self {
+            Plane::Y => frame.set_pixel_y(x, y, value),
+            Plane::U => frame.set_pixel_u(2 * x, 2 * y, value),
+            Plane::V => frame.set_pixel_v(2 * x, 2 * y, value),
+        }
+    }
+}
+
+/** standard JPEG luminance quantization table */
+pub fn q_mat_luma() -> Matrix<u8> {
     Matrix::from(
         &[
-            16., 11., 10., 16., 24., 40., 51., 61., // row 1
-            12., 12., 14., 19., 26., 58., 60., 55., // row 2
-            14., 13., 16., 24., 40., 57., 69., 56., // row 3
-            14., 17., 22., 29., 51., 87., 80., 62., // row 4
-            18., 22., 37., 56., 68., 109., 103., 77., // row 5
-            24., 35., 55., 64., 81., 104., 113., 92., // row 6
-            49., 64., 78., 87., 103., 121., 120., 101., // row 7
-            72., 92., 95., 98., 112., 100., 103., 99., // row 8
+            16, 11, 10, 16, 24, 40, 51, 61, // row 1
+            12, 12, 14, 19, 26, 58, 60, 55, // row 2
+            14, 13, 16, 24, 40, 57, 69, 56, // row 3
+            14, 17, 22, 29, 51, 87, 80, 62, // row 4
+            18, 22, 37, 56, 68, 109, 103, 77, // row 5
+            24, 35, 55, 64, 81, 104, 113, 92, // row 6
+            49, 64, 78, 87, 103, 121, 120, 101, // row 7
+            72, 92, 95, 98, 112, 100, 103, 99, // row 8
         ],
         TAIL_SIZE,
     )
 }
 
-fn c(i: f64) -> f64 {
-    if i == 0. {
-        (1. / TAIL_SIZE as f64).sqrt()
-    } else {
-        (2. / TAIL_SIZE as f64).sqrt()
-    }
+/** standard JPEG chrominance quantization table */
+pub fn q_mat_chroma() -> Matrix<u8> {
+    Matrix::from(
+        &[
+            17, 18, 24, 47, 99, 99, 99, 99, // row 1
+            18, 21, 26, 66, 99, 99, 99, 99, // row 2
+            24, 26, 56, 99, 99, 99, 99, 99, // row 3
+            47, 66, 99, 99, 99, 99, 99, 99, // row 4
+            99, 99, 99, 99, 99, 99, 99, 99, // row 5
+            99, 99, 99, 99, 99, 99, 99, 99, // row 6
+            99, 99, 99, 99, 99, 99, 99, 99, // row 7
+            99, 99, 99, 99, 99, 99, 99, 99, // row 8
+        ],
+        TAIL_SIZE,
+    )
 }
 
-fn dct_point(u: f64, v: f64, tail: &Matrix<f64>) -> f64 {
-    let mut sum = 0.;
-    let n = tail.len() as f64;
-    for i in 0..tail.len() {
-        for j in 0..tail.len() {
-            sum += tail.get(i, j)
-                * ((i as f64 + 0.5) * PI * u / n).cos()
-                * ((j as f64 + 0.5) * PI * v / n).cos();
+/** quantization tables of a picture before quality scaling */
+#[derive(Clone, Debug)]
+pub struct QuantTables {
+    pub luma: Matrix<u8>,
+    pub chroma: Matrix<u8>,
+}
+
+impl Default for QuantTables {
+    fn default() -> Self {
+        Self {
+            luma: q_mat_luma(),
+            chroma: q_mat_chroma(),
         }
     }
-    c(u) * c(v) * sum
 }
 
-fn idct_point(u: f64, v: f64, tail: &Matrix<f64>) -> f64 {
-    let mut sum = 0.;
-    let n = tail.len() as f64;
-    for i in 0..tail.len() {
-        for j in 0..tail.len() {
-            sum += c(i as f64)
-                * c(j as f64)
-                * tail.get(i, j)
-                * ((u + 0.5) * PI / n * i as f64).cos()
-                * ((v + 0.5) * PI / n * j as f64).cos();
-        }
+impl QuantTables {
+    /** quantization matrix of a plane scaled by `quality` (1-100) the way JPEG does */
+    pub fn q_mat(&self, plane: Plane, quality: u8) -> Matrix<f64> {
+        let quality = quality.clamp(1, 100) as u32;
+        let scale = if quality < 50 {
+            5000 / quality
+        } else {
+            200 - 2 * quality
+        };
+        let q_mat = match plane {
+            Plane::Y => &self.luma,
+            Plane::U | Plane::V => &self.chroma,
+        };
+        q_mat.convert(|e| ((e as u32 * scale + 50) / 100).clamp(1, 255) as f64)
     }
-    sum
 }
 
+/** orthonormal DCT-II basis, `get(i, u)` is the weight of sample `i` in coefficient `u` */
+fn cos_table(size: usize) -> &'static Matrix<f64> {
+    static TABLES: [OnceLock<Matrix<f64>>; 4] = [const { OnceLock::new() }; 4];
+    TABLES[size_index(size)].get_or_init(|| {
+        let n = size as f64;
+        let mut table = Matrix::new(size);
+        for i in 0..size {
+            for u in 0..size {
+                let c = if u == 0 {
+                    (1. / n).sqrt()
+                } else {
+                    (2. / n).sqrt()
+                };
+                table.set(i, u, c * ((i as f64 + 0.5) * PI * u as f64 / n).cos());
+            }
+        }
+        table
+    })
+}
+
+/* separable 2-D transform: 1-D pass over rows, then over columns */
 fn dct(tail: &mut Matrix<f64>) {
-    let src = tail.clone();
-    for u in 0..TAIL_SIZE {
-        for v in 0..TAIL_SIZE {
-            tail.set(u, v, dct_point(u as f64, v as f64, &src));
+    let n = tail.len();
+    let table = cos_table(n);
+    let mut rows = Matrix::new(n);
+    for j in 0..n {
+        for u in 0..n {
+            let mut sum = 0.;
+            for i in 0..n {
+                sum += tail.get(i, j) * table.get(i, u);
+            }
+            rows.set(u, j, sum);
+        }
+    }
+    for u in 0..n {
+        for v in 0..n {
+            let mut sum = 0.;
+            for j in 0..n {
+                sum += rows.get(u, j) * table.get(j, v);
+            }
+            tail.set(u, v, sum);
         }
     }
 }
 
 fn idct(tail: &mut Matrix<f64>) {
-    let src = tail.clone();
-    for u in 0..tail.len() {
-        for v in 0..TAIL_SIZE {
-            tail.set(u, v, idct_point(u as f64, v as f64, &src));
+    let n = tail.len();
+    let table = cos_table(n);
+    let mut rows = Matrix::new(n);
+    for v in 0..n {
+        for i in 0..n {
+            let mut sum = 0.;
+            for u in 0..n {
+                sum += tail.get(u, v) * table.get(i, u);
+            }
+            rows.set(i, v, sum);
+        }
+    }
+    for i in 0..n {
+        for j in 0..n {
+            let mut sum = 0.;
+            for v in 0..n {
+                sum += rows.get(i, v) * table.get(j, v);
+            }
+            tail.set(i, j, sum);
         }
     }
 }
 
-fn quantize(tail: &mut Matrix<f64>) {
-    let q_mat = q_mat();
+/**
+ * forward shifts for 8-bit samples are log2(N) - 1 and log2(N) + 6,
+ * which scales the coefficients by 128 / N compared to the orthonormal DCT
+*/
+fn int_dct(tail: &Matrix<i32>) -> Matrix<i32> {
+    let n = tail.len();
+    let table = int_table(n);
+    let shift1 = n.trailing_zeros() - 1;
+    let shift2 = n.trailing_zeros() + 6;
+    let mut rows = Matrix::new(n);
+    for j in 0..n {
+        for u in 0..n {
+            let mut sum = 0;
+            for i in 0..n {
+                sum += table.get(i, u) * tail.get(i, j);
+            }
+            rows.set(u, j, (sum + (1 << (shift1 - 1))) >> shift1);
+        }
+    }
+    let mut result = Matrix::new(n);
+    for u in 0..n {
+        for v in 0..n {
+            let mut sum = 0;
+            for j in 0..n {
+                sum += table.get(j, v) * rows.get(u, j);
+            }
+            result.set(u, v, (sum + (1 << (shift2 - 1))) >> shift2);
+        }
+    }
+    result
+}
+
+/* inverse shifts are 7 and 12, intermediate values are clipped to 16 bits */
+fn int_idct(tail: &Matrix<i32>) -> Matrix<i32> {
+    let clip = |e: i32| e.clamp(i16::MIN as i32, i16::MAX as i32);
+    let n = tail.len();
+    let table = int_table(n);
+    let mut rows = Matrix::new(n);
+    for v in 0..n {
+        for i in 0..n {
+            let mut sum = 0;
+            for u in 0..n {
+                sum += table.get(i, u) * clip(tail.get(u, v));
+            }
+            rows.set(i, v, clip((sum + 64) >> 7));
+        }
+    }
+    let mut result = Matrix::new(n);
+    for i in 0..n {
+        for j in 0..n {
+            let mut sum = 0;
+            for v in 0..n {
+                sum += table.get(j, v) * rows.get(i, v);
+            }
+            result.set(i, j, clip((sum + 2048) >> 12));
+        }
+    }
+    result
+}
+
+fn quantize(tail: &mut Matrix<f64>, q_mat: &Matrix<f64>) {
     for i in 0..tail.len() {
         for j in 0..tail.len() {
             let e = tail.get(i, j) / q_mat.get(i, j);
@@ -84,98 +347,975 @@ fn quantize(tail: &mut Matrix<f64>) {
     }
 }
 
-fn quantize_inv(tail: &mut Matrix<f64>) {
-    let q_mat = q_mat();
-    for i in 0..TAIL_SIZE {
-        for j in 0..TAIL_SIZE {
+fn quantize_inv(tail: &mut Matrix<f64>, q_mat: &Matrix<f64>) {
+    for i in 0..tail.len() {
+        for j in 0..tail.len() {
             let e = tail.get(i, j) * q_mat.get(i, j);
             tail.set(i, j, e);
         }
     }
 }
 
-fn f64_to_i8(tail: &Matrix<f64>) -> Matrix<i8> {
-    tail.convert(|e| {
-        if e - (e as i8 as f64) < 0.5 {
-            e as i8
-        } else {
-            e as i8 + 1
+/**
+ * Quantize a tail whose coefficients are already divided by their quantizer step.
+ * Going in zig-zag order, every AC level is compared against the level below it and zero,
+ * taking the bits of its own symbol and of the next non-zero level's symbol into account.
+ * The DC level is always rounded, errors in the mean of a tail are the most visible.
+*/
+fn rdo_quantize(tail: &Matrix<f64>, lambda: f64, writer: &CoeffWriter) -> Matrix<i16> {
+    let order = coeff::zigzag(tail.len());
+    let mut levels = f64_to_i16(tail);
+    let mut last = 0;
+    for k in 1..order.len() {
+        let (x, y) = order[k];
+        let rounded = levels.get(x, y);
+        if rounded == 0 {
+            continue;
         }
-    })
+        let next = (k + 1..order.len()).find(|i| levels.get(order[*i].0, order[*i].1) != 0);
+        /* bits of whatever follows a non-zero level at `from` */
+        let next_bits = |from: usize| match next {
+            Some(i) => writer.level_bits(i - from - 1, levels.get(order[i].0, order[i].1)),
+            None if from + 1 < order.len() => writer.eob_bits(),
+            None => 0.,
+        };
+        let value = tail.get(x, y).abs();
+        let mut best = (f64::MAX, 0);
+        for level in [rounded.abs(), rounded.abs() - 1, 0] {
+            let bits = if level == 0 {
+                next_bits(last)
+            } else {
+                writer.level_bits(k - last - 1, level) + next_bits(k)
+            };
+            let cost = (value - level as f64).powi(2) + lambda * bits;
+            if cost < best.0 {
+                best = (cost, level);
+            }
+        }
+        levels.set(x, y, rounded.signum() * best.1);
+        if best.1 != 0 {
+            last = k;
+        }
+    }
+    levels
+}
+
+/** weight of a block's statistics when adapting the rounding offsets */
+const ADAPT_RATE: f64 = 0.05;
+
+/** encoder side state of the quantizer, keeps the rounding offsets adapted so far */
+struct QuantState {
+    quantizer: Quantizer,
+    /** intra and inter rounding offsets */
+    offsets: [f64; 2],
+}
+
+impl QuantState {
+    fn new(quantizer: Quantizer) -> Self {
+        let offsets = match quantizer {
+            Quantizer::DeadZone { intra, inter, .. } => [intra, inter],
+            _ => [0.5; 2],
+        };
+        Self { quantizer, offsets }
+    }
+    /** quantize a tail whose coefficients are already divided by their quantizer step */
+    fn quantize(
+        &mut self,
+        tail: &Matrix<f64>,
+        kind: BlockKind,
+        writer: &CoeffWriter,
+    ) -> Matrix<i16> {
+        match self.quantizer {
+            Quantizer::Round => f64_to_i16(tail),
+            Quantizer::DeadZone { adaptive, .. } => {
+                let offset = &mut self.offsets[kind as usize];
+                /* rounding the magnitude keeps negative coefficients symmetric to positive ones */
+                let levels = tail.convert(|e| {
+                    let level = (e.abs() + *offset).floor().min(i16::MAX as f64);
+                    (e.signum() * level) as i16
+                });
+                if adaptive {
+                    let mut sum = 0.;
+                    let mut count = 0;
+                    for i in 0..tail.len() {
+                        for j in 0..tail.len() {
+                            let level = levels.get(i, j);
+                            if level != 0 {
+                                sum += tail.get(i, j).abs() - level.unsigned_abs() as f64;
+                                count += 1;
+                            }
+                        }
+                    }
+                    /*
+                     * coefficients left below their level mean the intervals sit too low,
+                     * a smaller offset moves them up
+                     */
+                    if count > 0 {
+                        *offset = (*offset + ADAPT_RATE * sum / count as f64).clamp(0., 0.5);
+                    }
+                }
+                levels
+            }
+            Quantizer::Rdo { lambda } => rdo_quantize(tail, lambda, writer),
+        }
+    }
+}
+
+/** round to the nearest level, saturating to the range the entropy coder can store */
+fn f64_to_i16(tail: &Matrix<f64>) -> Matrix<i16> {
+    tail.convert(|e| e.round().clamp(-i16::MAX as f64, i16::MAX as f64) as i16)
 }
 
-fn i8_to_f64(tail: &Matrix<i8>) -> Matrix<f64> {
+fn i16_to_f64(tail: &Matrix<i16>) -> Matrix<f64> {
     tail.convert(|e| e as f64)
 }
 
-fn u8_to_i8(tail: &Matrix<u8>) -> Matrix<i8> {
-    tail.convert(|e| (e as isize - 128) as i8)
+/** largest residual a decoded tail keeps, far past any pixel difference but safe to add to a prediction */
+const MAX_RESIDUAL: f64 = (1 << 16) as f64;
+
+/** round a decoded residual, saturating the ones no real picture produces */
+fn f64_to_i32(tail: &Matrix<f64>) -> Matrix<i32> {
+    tail.convert(|e| e.round().clamp(-MAX_RESIDUAL, MAX_RESIDUAL) as i32)
+}
+
+/** difference between a tail and its prediction */
+fn residual(tail: &Matrix<u8>, pred: &Matrix<i32>) -> Matrix<i32> {
+    let mut residual = Matrix::new(tail.len());
+    for i in 0..tail.len() {
+        for j in 0..tail.len() {
+            residual.set(i, j, tail.get(i, j) as i32 - pred.get(i, j));
+        }
+    }
+    residual
 }
 
-fn i8_to_u8(tail: &Matrix<i8>) -> Matrix<u8> {
-    tail.convert(|e| (e as isize + 128) as u8)
+/** add a decoded residual to its prediction */
+fn reconstruct(pred: &Matrix<i32>, residual: &Matrix<i32>) -> Matrix<u8> {
+    let mut tail = Matrix::new(pred.len());
+    for i in 0..pred.len() {
+        for j in 0..pred.len() {
+            tail.set(
+                i,
+                j,
+                (pred.get(i, j) + residual.get(i, j)).clamp(0, 255) as u8,
+            );
+        }
+    }
+    tail
 }
 
-fn encode_tail(tail: &Matrix<u8>) -> Matrix<u8> {
-    let mut tail = i8_to_f64(&u8_to_i8(tail));
-    dct(&mut tail);
-    quantize(&mut tail);
-    i8_to_u8(&f64_to_i8(&tail))
+/** quantization matrix of an `n`x`n` tail, resampled from the 8x8 one */
+fn q_mat_sized(q_mat: &Matrix<f64>, n: usize) -> Matrix<f64> {
+    let mut result = Matrix::new(n);
+    for i in 0..n {
+        for j in 0..n {
+            result.set(i, j, q_mat.get(i * TAIL_SIZE / n, j * TAIL_SIZE / n));
+        }
+    }
+    result
 }
 
-fn decode_tail(tail: &Matrix<u8>) -> Matrix<u8> {
-    let mut tail = i8_to_f64(&u8_to_i8(tail));
-    quantize_inv(&mut tail);
-    idct(&mut tail);
-    i8_to_u8(&f64_to_i8(&tail))
+/**
+ * Transform and quantize the residual of a tail of any size.
+ * Coefficients are normalized to the scale of an 8x8 orthonormal DCT before quantization,
+ * so that the same resampled quantization matrix fits all sizes.
+*/
+fn encode_tail(
+    tail: &Matrix<i32>,
+    transform: Transform,
+    q_mat: &Matrix<f64>,
+    quant: &mut QuantState,
+    writer: &CoeffWriter,
+) -> Matrix<i16> {
+    let n = tail.len();
+    let mut tail = match transform {
+        Transform::Float => {
+            let mut tail = tail.convert(|e| e as f64);
+            dct(&mut tail);
+            tail.convert(|e| e * TAIL_SIZE as f64 / n as f64)
+        }
+        Transform::Integer => int_dct(tail).convert(|e| e as f64 / INT_SCALE as f64),
+    };
+    quantize(&mut tail, q_mat);
+    /* there is no inter prediction yet, every tail is intra coded */
+    quant.quantize(&tail, BlockKind::Intra, writer)
 }
 
-pub fn encode_frame<T>(src: &T) -> T
-where
-    T: YUVFrame + Clone,
-{
-    let mut dst = src.clone();
-    let (width, height) = src.get_resolution();
-    for i in 0..width / TAIL_SIZE {
-        for j in 0..height / TAIL_SIZE {
-            let mut mat = Matrix::new(TAIL_SIZE);
-            for x in 0..TAIL_SIZE {
-                for y in 0..TAIL_SIZE {
-                    mat.set(x, y, dst.get_pixel_y(TAIL_SIZE * i + x, TAIL_SIZE * j + y));
+/** dequantize and inverse transform a tail back to its residual */
+fn decode_tail(tail: &Matrix<i16>, transform: Transform, q_mat: &Matrix<f64>) -> Matrix<i32> {
+    let n = tail.len();
+    match transform {
+        Transform::Float => {
+            let mut tail = i16_to_f64(tail);
+            quantize_inv(&mut tail, q_mat);
+            let mut tail = tail.convert(|e| e * n as f64 / TAIL_SIZE as f64);
+            idct(&mut tail);
+            f64_to_i32(&tail)
+        }
+        Transform::Integer => {
+            /* dequantization stays in integers so that every decoder gets the same result */
+            let mut tail = tail.convert(|e| e as i32);
+            for i in 0..n {
+                for j in 0..n {
+                    let e = tail.get(i, j) * q_mat.get(i, j) as i32 * INT_SCALE;
+                    tail.set(i, j, e);
                 }
             }
-            let mat = encode_tail(&mat);
-            for x in 0..TAIL_SIZE {
-                for y in 0..TAIL_SIZE {
-                    dst.set_pixel_y(TAIL_SIZE * i + x, TAIL_SIZE * j + y, mat.get(x, y));
+            int_idct(&tail)
+        }
+    }
+}
+
+/**
+ * copy the `n`x`n` tail whose top left pixel is at (`x`, `y`) out of a plane,
+ * tails crossing the right or bottom edge are padded by repeating the edge pixels
+*/
+fn get_tail<T: YUVFrame>(frame: &T, plane: Plane, x: usize, y: usize, n: usize) -> Matrix<u8> {
+    let (width, height) = plane.resolution(frame);
+    let mut tail = Matrix::new(n);
+    for i in 0..n {
+        for j in 0..n {
+            let pixel = plane.get_pixel(frame, (x + i).min(width - 1), (y + j).min(height - 1));
+            tail.set(i, j, pixel);
+        }
+    }
+    tail
+}
+
+/** write a tail back into a plane, the padding outside the plane is cropped */
+fn set_tail<T: YUVFrame>(frame: &mut T, plane: Plane, x: usize, y: usize, tail: &Matrix<u8>) {
+    let (width, height) = plane.resolution(frame);
+    for i in 0..tail.len().min(width - x) {
+        for j in 0..tail.len().min(height - y) {
+            plane.set_pixel(frame, x + i, y + j, tail.get(i, j));
+        }
+    }
+}
+
+fn tail_kind(tail: &Matrix<i16>) -> TailKind {
+    let mut kind = TailKind::Empty;
+    for i in 0..tail.len() {
+        for j in 0..tail.len() {
+            if tail.get(i, j) != 0 {
+                if i != 0 || j != 0 {
+                    return TailKind::Textured;
                 }
+                kind = TailKind::Flat;
             }
         }
     }
-    dst
+    kind
 }
 
-pub fn decode_frame<T>(src: &T) -> T
-where
-    T: YUVFrame + Clone,
+/** DC levels of the residuals coded so far in a plane, stored per smallest tail, used for DPCM */
+struct DcPredictor {
+    levels: Vec<i16>,
+    cols: usize,
+    rows: usize,
+}
+
+impl DcPredictor {
+    fn new(width: usize, height: usize) -> Self {
+        let cols = width.div_ceil(MIN_TAIL_SIZE);
+        let rows = height.div_ceil(MIN_TAIL_SIZE);
+        Self {
+            levels: vec![0; cols * rows],
+            cols,
+            rows,
+        }
+    }
+    /** DC level of the tail left of (`x`, `y`), or of the upper one at the left edge */
+    fn predict(&self, x: usize, y: usize) -> i16 {
+        let (i, j) = (x / MIN_TAIL_SIZE, y / MIN_TAIL_SIZE);
+        if i > 0 {
+            self.levels[j * self.cols + i - 1]
+        } else if j > 0 {
+            self.levels[(j - 1) * self.cols + i]
+        } else {
+            0
+        }
+    }
+    fn update(&mut self, x: usize, y: usize, n: usize, level: i16) {
+        let (i, j) = (x / MIN_TAIL_SIZE, y / MIN_TAIL_SIZE);
+        let units = n / MIN_TAIL_SIZE;
+        for j in j..(j + units).min(self.rows) {
+            for i in i..(i + units).min(self.cols) {
+                self.levels[j * self.cols + i] = level;
+            }
+        }
+    }
+}
+
+/** coding parameters of one plane */
+struct PlaneParams {
+    plane: Plane,
+    width: usize,
+    height: usize,
+    transform: Transform,
+    /** 8x8 quantization matrix of the plane before any delta-QP */
+    base_q_mat: Matrix<f64>,
+    /** 8x8 quantization matrix of the current region */
+    q_mat: Matrix<f64>,
+    /** quantization matrices of the current region resampled for every tail size */
+    q_mats: Vec<Matrix<f64>>,
+}
+
+impl PlaneParams {
+    fn new<T: YUVFrame>(
+        frame: &T,
+        plane: Plane,
+        transform: Transform,
+        q_tables: &QuantTables,
+        quality: u8,
+    ) -> Self {
+        let (width, height) = plane.resolution(frame);
+        let q_mat = q_tables.q_mat(plane, quality);
+        let q_mats = tail_sizes().map(|n| q_mat_sized(&q_mat, n)).collect();
+        Self {
+            plane,
+            width,
+            height,
+            transform,
+            base_q_mat: q_mat.clone(),
+            q_mat,
+            q_mats,
+        }
+    }
+    fn q_mat(&self, n: usize) -> &Matrix<f64> {
+        &self.q_mats[size_index(n)]
+    }
+    /** DC quantizer step of the current region */
+    fn q_dc(&self) -> f64 {
+        self.q_mat.get(0, 0)
+    }
+    /** scale the quantization matrices for a region coded with `delta_qp` */
+    fn set_delta_qp(&mut self, delta_qp: i8) {
+        self.q_mat = self.base_q_mat.convert(|e| scale_step(e, delta_qp));
+        self.q_mats = tail_sizes().map(|n| q_mat_sized(&self.q_mat, n)).collect();
+    }
+}
+
+/** 2^(k/6) in 1/64 units, a quantizer step doubles every 6 QP */
+const QP_SCALE: [u32; 6] = [64, 72, 81, 91, 102, 114];
+/** largest delta-QP a region can be coded with, a factor of 4 on the quantizer step */
+pub const MAX_DELTA_QP: i8 = 12;
+
+/** scale an integer quantizer step by 2^(`delta_qp`/6), in integers so that every decoder gets the same step */
+fn scale_step(step: f64, delta_qp: i8) -> f64 {
+    let shift = delta_qp.div_euclid(6) as i32;
+    let up = shift.max(0);
+    let down = 6 + (-shift).max(0);
+    let scaled = step as u32 * QP_SCALE[delta_qp.rem_euclid(6) as usize];
+    (((scaled << up) + (1 << (down - 1))) >> down).max(1) as f64
+}
+
+/** top left corners of the 32x32 coding regions of a `width`x`height` plane in raster order */
+fn regions(width: usize, height: usize) -> impl Iterator<Item = (usize, usize)> {
+    (0..height)
+        .step_by(MAX_TAIL_SIZE)
+        .flat_map(move |y| (0..width).step_by(MAX_TAIL_SIZE).map(move |x| (x, y)))
+}
+
+/** number of 32x32 coding regions of all planes of a `width`x`height` picture, each one takes at least one symbol */
+pub fn region_count(width: usize, height: usize) -> usize {
+    let count = |width: usize, height: usize| {
+        width.div_ceil(MAX_TAIL_SIZE) * height.div_ceil(MAX_TAIL_SIZE)
+    };
+    count(width, height) + 2 * count(width.div_ceil(2), height.div_ceil(2))
+}
+
+/**
+ * Most symbols a `width`x`height` picture can take: a region has a delta-QP, the split flags of a
+ * quadtree down to 4x4 tails, a mode per 4x4 tail and at most one coefficient symbol per sample.
+*/
+pub fn max_symbols(width: usize, height: usize) -> usize {
+    const REGION_SYMBOLS: usize = 1 + (1 + 4 + 16) + 64 + MAX_TAIL_SIZE * MAX_TAIL_SIZE;
+    region_count(width, height) * REGION_SYMBOLS
+}
+
+/** rectangle of the picture in luma pixels whose regions get `qp_offset` */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RoiRect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+    pub qp_offset: i8,
+}
+
+/**
+ * Region of interest map supplied by the caller, negative offsets keep content sharp,
+ * positive ones let it degrade.
+ * Where several offsets fall into one coding region the lowest one is used,
+ * so that protected content is never quantized coarser than asked.
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RoiMap {
+    /** rectangles, regions outside of all of them keep their delta-QP */
+    Rects(Vec<RoiRect>),
+    /** QP offset of every 32x32 luma block in raster order with `cols` blocks per row */
+    Grid { cols: usize, offsets: Vec<i8> },
+}
+
+impl RoiMap {
+    /** QP offset of the luma area from (`x0`, `y0`) to (`x1`, `y1`), exclusive */
+    fn offset(&self, x0: usize, y0: usize, x1: usize, y1: usize) -> i8 {
+        match self {
+            Self::Rects(rects) => rects
+                .iter()
+                .filter(|r| {
+                    /* rectangles may reach past the plane, up to `usize::MAX` */
+                    r.x < x1
+                        && r.x.saturating_add(r.width) > x0
+                        && r.y < y1
+                        && r.y.saturating_add(r.height) > y0
+                })
+                .map(|r| r.qp_offset)
+                .min()
+                .unwrap_or(0),
+            Self::Grid { cols, offsets } => {
+                let (i0, j0) = (x0 / MAX_TAIL_SIZE, y0 / MAX_TAIL_SIZE);
+                let (i1, j1) = (x1.div_ceil(MAX_TAIL_SIZE), y1.div_ceil(MAX_TAIL_SIZE));
+                (j0..j1)
+                    .flat_map(|j| (i0..i1.min(*cols)).map(move |i| j * cols + i))
+                    .filter_map(|i| offsets.get(i).copied())
+                    .min()
+                    .unwrap_or(0)
+            }
+        }
+    }
+}
+
+/** delta-QP of every 32x32 coding region, one grid per plane */
+#[derive(Clone, Debug)]
+pub struct DeltaQps {
+    /** number of region columns and the delta-QPs in raster order of each plane */
+    grids: Vec<(usize, Vec<i8>)>,
+}
+
+impl DeltaQps {
+    /** all regions of `frame` at delta-QP 0 */
+    pub fn new<T: YUVFrame>(frame: &T) -> Self {
+        let grids = [Plane::Y, Plane::U, Plane::V]
+            .into_iter()
+            .map(|plane| {
+                let (width, height) = plane.resolution(frame);
+                let cols = width.div_ceil(MAX_TAIL_SIZE);
+                (cols, vec![0; cols * height.div_ceil(MAX_TAIL_SIZE)])
+            })
+            .collect();
+        Self { grids }
+    }
+    /**
+     * Delta-QPs following the spatial activity of `src`.
+     * Every region moves `strength` QP per doubling of its pixel variance away from the plane average,
+     * so flat regions get finer quantization and busy texture coarser.
+     */
+    pub fn from_activity<T: YUVFrame>(src: &T, strength: f64) -> Self {
+        let mut delta_qps = Self::new(src);
+        for (plane, (_, grid)) in [Plane::Y, Plane::U, Plane::V]
+            .into_iter()
+            .zip(&mut delta_qps.grids)
+        {
+            let (width, height) = plane.resolution(src);
+            let activity: Vec<f64> = regions(width, height)
+                .map(|(x, y)| {
+                    let pixels: Vec<f64> = (y..(y + MAX_TAIL_SIZE).min(height))
+                        .flat_map(|j| (x..(x + MAX_TAIL_SIZE).min(width)).map(move |i| (i, j)))
+                        .map(|(i, j)| plane.get_pixel(src, i, j) as f64)
+                        .collect();
+                    let mean = pixels.iter().sum::<f64>() / pixels.len() as f64;
+                    let variance = pixels.iter().map(|p| (p - mean).powi(2)).sum::<f64>()
+                        / pixels.len() as f64;
+                    (variance + 1.).log2()
+                })
+                .collect();
+            let average = activity.iter().sum::<f64>() / activity.len() as f64;
+            for (delta_qp, activity) in grid.iter_mut().zip(activity) {
+                let offset = (strength * (activity - average)).round();
+                *delta_qp = offset.clamp(-MAX_DELTA_QP as f64, MAX_DELTA_QP as f64) as i8;
+            }
+        }
+        delta_qps
+    }
+    /** delta-QP of the region containing pixel (`x`, `y`) of a plane */
+    pub fn get(&self, plane: Plane, x: usize, y: usize) -> i8 {
+        let (cols, grid) = &self.grids[plane as usize];
+        grid[(y / MAX_TAIL_SIZE) * cols + x / MAX_TAIL_SIZE]
+    }
+    /** add `offset` to the delta-QP of the region containing pixel (`x`, `y`) of a plane */
+    pub fn add(&mut self, plane: Plane, x: usize, y: usize, offset: i8) {
+        let (cols, grid) = &mut self.grids[plane as usize];
+        let delta_qp = &mut grid[(y / MAX_TAIL_SIZE) * *cols + x / MAX_TAIL_SIZE];
+        *delta_qp = delta_qp
+            .saturating_add(offset)
+            .clamp(-MAX_DELTA_QP, MAX_DELTA_QP);
+    }
+    /** add the offsets of a region of interest map, chroma regions take the luma area they cover */
+    pub fn add_roi(&mut self, roi: &RoiMap) {
+        for plane in [Plane::Y, Plane::U, Plane::V] {
+            let (cols, count) = (
+                self.grids[plane as usize].0,
+                self.grids[plane as usize].1.len(),
+            );
+            let scale = if plane == Plane::Y { 1 } else { 2 };
+            let size = MAX_TAIL_SIZE * scale;
+            for i in 0..count {
+                let (x, y) = (i % cols * MAX_TAIL_SIZE, i / cols * MAX_TAIL_SIZE);
+                let offset = roi.offset(x * scale, y * scale, x * scale + size, y * scale + size);
+                self.add(plane, x, y, offset);
+            }
+        }
+    }
+}
+
+/** tails chosen by the encoder for a square region */
+enum CodingTree {
+    /** one tail covering the region with its prediction mode */
+    Tail(IntraMode, Matrix<i16>),
+    /** four quarters in z-order, `None` for quarters outside of the plane */
+    Split(Vec<Option<CodingTree>>),
+}
+
+/**
+ * Choose between coding the `n`x`n` region at (`x`, `y`) as one tail or as four smaller ones,
+ * whichever costs less in distortion plus `lambda` times the estimated bits.
+ * The chosen tails are decoded into `recon`, which later tails are predicted from.
+ * Returns the cost and the chosen tree.
+*/
+#[allow(clippy::too_many_arguments)]
+fn choose_tails<T: YUVFrame>(
+    src: &T,
+    recon: &mut YUV420Frame,
+    writer: &CoeffWriter,
+    params: &PlaneParams,
+    quant: &mut QuantState,
+    lambda: f64,
+    x: usize,
+    y: usize,
+    n: usize,
+) -> (f64, CodingTree) {
+    let plane = params.plane;
+    let tail = get_tail(src, plane, x, y, n);
+    let (mode, pred) = intra::choose_mode(recon, plane, x, y, &tail);
+    let residual = residual(&tail, &pred);
+    let levels = encode_tail(&residual, params.transform, params.q_mat(n), quant, writer);
+    let decoded = reconstruct(
+        &pred,
+        &decode_tail(&levels, params.transform, params.q_mat(n)),
+    );
+    set_tail(recon, plane, x, y, &decoded);
+    let mut distortion = 0.;
+    for i in 0..n.min(params.width - x) {
+        for j in 0..n.min(params.height - y) {
+            distortion += (tail.get(i, j) as f64 - decoded.get(i, j) as f64).powi(2);
+        }
+    }
+    let bits = writer.symbol_bits(mode.symbol()) + writer.estimate_bits(&levels);
+    let cost = distortion + lambda * bits;
+    if n == MIN_TAIL_SIZE {
+        return (cost, CodingTree::Tail(mode, levels));
+    }
+
+    let half = n / 2;
+    let mut split_cost = 0.;
+    let mut quarters = Vec::new();
+    for (qx, qy) in [(x, y), (x + half, y), (x, y + half), (x + half, y + half)] {
+        if qx < params.width && qy < params.height {
+            let (quarter_cost, quarter) =
+                choose_tails(src, recon, writer, params, quant, lambda, qx, qy, half);
+            split_cost += quarter_cost;
+            quarters.push(Some(quarter));
+        } else {
+            quarters.push(None);
+        }
+    }
+    if split_cost < cost {
+        (split_cost, CodingTree::Split(quarters))
+    } else {
+        /* the quarters overwrote the single tail */
+        set_tail(recon, plane, x, y, &decoded);
+        (cost, CodingTree::Tail(mode, levels))
+    }
+}
+
+/**
+ * write a coding tree of a `n`x`n` region, quarters outside of the plane are left out,
+ * tails carry the difference to the predicted DC level of their residual
+*/
+fn write_tree(
+    writer: &mut CoeffWriter,
+    dc_pred: &mut DcPredictor,
+    tree: CodingTree,
+    x: usize,
+    y: usize,
+    n: usize,
+) {
+    match tree {
+        CodingTree::Tail(mode, mut levels) => {
+            if n > MIN_TAIL_SIZE {
+                writer.write_symbol(0);
+            }
+            writer.write_symbol(mode.symbol());
+            let dc = levels.get(0, 0);
+            levels.set(0, 0, dc.wrapping_sub(dc_pred.predict(x, y)));
+            dc_pred.update(x, y, n, dc);
+            writer.write_block(&levels);
+        }
+        CodingTree::Split(quarters) => {
+            writer.write_symbol(1);
+            let half = n / 2;
+            let positions = [(x, y), (x + half, y), (x, y + half), (x + half, y + half)];
+            for (quarter, (qx, qy)) in quarters.into_iter().zip(positions) {
+                if let Some(quarter) = quarter {
+                    write_tree(writer, dc_pred, quarter, qx, qy, half);
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Transform and quantize every plane of `src`.
+ * Planes are split into 32x32 regions in raster order, each region is a quadtree of tails
+ * with a split flag for every node larger than the smallest tail size.
+ * Every tail is predicted from the decoded pixels around it, its mode symbol
+ * comes before the coefficients of the residual, whose DC level is predicted from the previous tail.
+ * With `delta_qps`, every region starts with its delta-QP as a signed byte.
+*/
+pub fn encode_frame<T>(
+    src: &T,
+    writer: &mut CoeffWriter,
+    transform: Transform,
+    q_tables: &QuantTables,
+    quality: u8,
+    quantizer: Quantizer,
+    delta_qps: Option<&DeltaQps>,
+) where
+    T: YUVFrame,
 {
-    let mut dst = src.clone();
     let (width, height) = src.get_resolution();
-    for i in 0..width / TAIL_SIZE {
-        for j in 0..height / TAIL_SIZE {
-            let mut mat = Matrix::new(TAIL_SIZE);
-            for x in 0..TAIL_SIZE {
-                for y in 0..TAIL_SIZE {
-                    mat.set(x, y, dst.get_pixel_y(TAIL_SIZE * i + x, TAIL_SIZE * j + y));
+    let mut recon = YUV420Frame::new(width, height);
+    for plane in [Plane::Y, Plane::U, Plane::V] {
+        let mut params = PlaneParams::new(src, plane, transform, q_tables, quality);
+        let mut quant = QuantState::new(quantizer);
+        let mut dc_pred = DcPredictor::new(params.width, params.height);
+        for (x, y) in regions(params.width, params.height) {
+            if let Some(delta_qps) = delta_qps {
+                let delta_qp = delta_qps.get(plane, x, y);
+                writer.write_symbol(delta_qp as u8);
+                params.set_delta_qp(delta_qp);
+            }
+            /* Lagrange multiplier of a bit in squared error, grows with the squared quantizer step */
+            let lambda = 0.09 * params.q_dc().powi(2);
+            let (_, tree) = choose_tails(
+                src,
+                &mut recon,
+                writer,
+                &params,
+                &mut quant,
+                lambda,
+                x,
+                y,
+                MAX_TAIL_SIZE,
+            );
+            write_tree(writer, &mut dc_pred, tree, x, y, MAX_TAIL_SIZE);
+        }
+    }
+}
+
+/** decode the `n`x`n` region at (`x`, `y`) */
+#[allow(clippy::too_many_arguments)]
+fn decode_tree<T: YUVFrame>(
+    dst: &mut T,
+    reader: &mut CoeffReader,
+    params: &PlaneParams,
+    dc_pred: &mut DcPredictor,
+    map: &mut TailMap,
+    x: usize,
+    y: usize,
+    n: usize,
+) -> Result<(), DecodeError> {
+    let split = n > MIN_TAIL_SIZE
+        && match reader.read_symbol()? {
+            0 => false,
+            1 => true,
+            _ => return Err(DecodeError::InvalidData),
+        };
+    if split {
+        let half = n / 2;
+        for (qx, qy) in [(x, y), (x + half, y), (x, y + half), (x + half, y + half)] {
+            if qx < params.width && qy < params.height {
+                decode_tree(dst, reader, params, dc_pred, map, qx, qy, half)?;
+            }
+        }
+        return Ok(());
+    }
+    let mode = IntraMode::from_symbol(reader.read_symbol()?)?;
+    let mut levels = reader.read_block(n)?;
+    let dc = levels.get(0, 0).wrapping_add(dc_pred.predict(x, y));
+    levels.set(0, 0, dc);
+    dc_pred.update(x, y, n, dc);
+    map.set(x, y, n, tail_kind(&levels), params.q_dc() as i32);
+    let pred = intra::predict(dst, params.plane, x, y, n, mode);
+    let tail = reconstruct(
+        &pred,
+        &decode_tail(&levels, params.transform, params.q_mat(n)),
+    );
+    set_tail(dst, params.plane, x, y, &tail);
+    Ok(())
+}
+
+/**
+ * decode every tail into `dst`, `delta_qp` tells whether regions start with a delta-QP.
+ * Returns the tails decoded in each plane for deblocking.
+*/
+pub fn decode_frame<T>(
+    dst: &mut T,
+    reader: &mut CoeffReader,
+    transform: Transform,
+    q_tables: &QuantTables,
+    quality: u8,
+    delta_qp: bool,
+) -> Result<Vec<TailMap>, DecodeError>
+where
+    T: YUVFrame,
+{
+    let mut maps = Vec::new();
+    for plane in [Plane::Y, Plane::U, Plane::V] {
+        let mut params = PlaneParams::new(dst, plane, transform, q_tables, quality);
+        let mut dc_pred = DcPredictor::new(params.width, params.height);
+        let mut map = TailMap::new(plane, params.width, params.height);
+        for (x, y) in regions(params.width, params.height) {
+            if delta_qp {
+                let delta_qp = reader.read_symbol()? as i8;
+                if !(-MAX_DELTA_QP..=MAX_DELTA_QP).contains(&delta_qp) {
+                    return Err(DecodeError::InvalidData);
+                }
+                params.set_delta_qp(delta_qp);
+            }
+            let n = MAX_TAIL_SIZE;
+            decode_tree(dst, reader, &params, &mut dc_pred, &mut map, x, y, n)?;
+        }
+        maps.push(map);
+    }
+    Ok(maps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entropy::EntropyBackend;
+
+    /** deterministic samples in `-range..range` */
+    fn samples(n: usize, range: i32, seed: u32) -> Matrix<i32> {
+        let mut state = seed;
+        let mut tail = Matrix::new(n);
+        for i in 0..n {
+            for j in 0..n {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                tail.set(i, j, (state >> 16) as i32 % range - range / 2);
+            }
+        }
+        tail
+    }
+
+    fn c(u: f64, n: f64) -> f64 {
+        if u == 0. {
+            (1. / n).sqrt()
+        } else {
+            (2. / n).sqrt()
+        }
+    }
+
+    /** the original transform, computing every coefficient from all samples */
+    fn dct_point(u: f64, v: f64, tail: &Matrix<f64>) -> f64 {
+        let mut sum = 0.;
+        let n = tail.len() as f64;
+        for i in 0..tail.len() {
+            for j in 0..tail.len() {
+                sum += tail.get(i, j)
+                    * ((i as f64 + 0.5) * PI * u / n).cos()
+                    * ((j as f64 + 0.5) * PI * v / n).cos();
+            }
+        }
+        c(u, n) * c(v, n) * sum
+    }
+
+    fn idct_point(u: f64, v: f64, tail: &Matrix<f64>) -> f64 {
+        let mut sum = 0.;
+        let n = tail.len() as f64;
+        for i in 0..tail.len() {
+            for j in 0..tail.len() {
+                sum += c(i as f64, n)
+                    * c(j as f64, n)
+                    * tail.get(i, j)
+                    * ((u + 0.5) * PI / n * i as f64).cos()
+                    * ((v + 0.5) * PI / n * j as f64).cos();
+            }
+        }
+        sum
+    }
+
+    #[test]
+    fn dct_matches_reference() {
+        for n in [4, 8, 16, 32] {
+            let src = samples(n, 512, n as u32).convert(|e| e as f64);
+            let mut fast = src.clone();
+            dct(&mut fast);
+            let mut fast_inv = src.clone();
+            idct(&mut fast_inv);
+            for u in 0..n {
+                for v in 0..n {
+                    let expected = dct_point(u as f64, v as f64, &src);
+                    assert!(
+                        (fast.get(u, v) - expected).abs() < 1e-9,
+                        "dct {n}x{n} at ({u}, {v})"
+                    );
+                    let expected = idct_point(u as f64, v as f64, &src);
+                    assert!(
+                        (fast_inv.get(u, v) - expected).abs() < 1e-9,
+                        "idct {n}x{n} at ({u}, {v})"
+                    );
+                }
+            }
+        }
+    }
+
+    /** FNV-1a hash of the decoded residual of every tail size */
+    fn int_decode_hashes() -> Vec<u64> {
+        [4, 8, 16, 32]
+            .into_iter()
+            .map(|n| {
+                let levels = samples(n, 64, n as u32).convert(|e| e as i16);
+                let q_mat = Matrix::from(&vec![10.; n * n], n);
+                let residual = decode_tail(&levels, Transform::Integer, &q_mat);
+                let mut hash = 0xcbf2_9ce4_8422_2325u64;
+                for j in 0..n {
+                    for i in 0..n {
+                        hash =
+                            (hash ^ residual.get(i, j) as u32 as u64).wrapping_mul(0x100_0000_01b3);
+                    }
+                }
+                hash
+            })
+            .collect()
+    }
+
+    /* every decoder has to reproduce these exactly, a change here breaks existing pictures */
+    #[test]
+    fn int_decode_is_pinned() {
+        assert_eq!(
+            int_decode_hashes(),
+            [
+                0x3808_bb0d_25a6_f612,
+                0x16f2_f631_839d_9c29,
+                0x76b5_3cee_733d_c7be,
+                0x5ade_5e5e_4775_5dcd
+            ]
+        );
+    }
+
+    #[test]
+    fn int_transform_round_trip() {
+        /* the larger integer bases are only close to orthogonal, so their rounding errors add up */
+        for (n, tolerance) in [(4, 1), (8, 1), (16, 3), (32, 4)] {
+            for seed in 0..16 {
+                let src = samples(n, 511, seed);
+                let decoded = int_idct(&int_dct(&src));
+                for i in 0..n {
+                    for j in 0..n {
+                        let error = (decoded.get(i, j) - src.get(i, j)).abs();
+                        assert!(
+                            error <= tolerance,
+                            "{n}x{n} at ({i}, {j}) is off by {error}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn extreme_levels_reconstruct() {
+        /* largest quantizer step of all-255 tables at quality 1 and the largest delta-QP */
+        let step = scale_step(255., MAX_DELTA_QP);
+        for n in [MIN_TAIL_SIZE, MAX_TAIL_SIZE] {
+            let mut q_mat = Matrix::new(n);
+            let mut levels = Matrix::new(n);
+            for i in 0..n {
+                for j in 0..n {
+                    q_mat.set(i, j, step);
+                    levels.set(
+                        i,
+                        j,
+                        if (i + j) % 2 == 0 {
+                            i16::MAX
+                        } else {
+                            -i16::MAX
+                        },
+                    );
                 }
             }
-            let mat = decode_tail(&mat);
-            for x in 0..TAIL_SIZE {
-                for y in 0..TAIL_SIZE {
-                    dst.set_pixel_y(TAIL_SIZE * i + x, TAIL_SIZE * j + y, mat.get(x, y));
+            for transform in [Transform::Float, Transform::Integer] {
+                let mut pred = Matrix::new(n);
+                for i in 0..n {
+                    for j in 0..n {
+                        pred.set(i, j, 255);
+                    }
                 }
+                reconstruct(&pred, &decode_tail(&levels, transform, &q_mat));
+                let negative = levels.convert(|e| -e);
+                reconstruct(&pred, &decode_tail(&negative, transform, &q_mat));
             }
         }
     }
-    dst
+
+    /** decode an 8x8 picture from raw symbols, one 32x32 region per plane */
+    fn decode_symbols(symbols: &[u8], delta_qp: bool) -> Result<(), DecodeError> {
+        let mut writer = CoeffWriter::new(EntropyBackend::Huffman);
+        for symbol in symbols {
+            writer.write_symbol(*symbol);
+        }
+        let data = writer.finish();
+        let mut reader = CoeffReader::new(&data, EntropyBackend::Huffman, usize::MAX)?;
+        let mut frame = YUV420Frame::new(8, 8);
+        let q_tables = QuantTables::default();
+        decode_frame(
+            &mut frame,
+            &mut reader,
+            Transform::Float,
+            &q_tables,
+            50,
+            delta_qp,
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_syntax_elements() {
+        /* no split, DC mode and an empty block in every plane */
+        assert_eq!(decode_symbols(&[0; 9], false), Ok(()));
+        assert_eq!(decode_symbols(&[0; 12], true), Ok(()));
+        let delta_qp = MAX_DELTA_QP as u8;
+        assert_eq!(decode_symbols(&[delta_qp, 0, 0, 0].repeat(3), true), Ok(()));
+
+        /* a split flag other than 0 or 1 */
+        assert_eq!(
+            decode_symbols(&[2, 0, 0], false),
+            Err(DecodeError::InvalidData)
+        );
+        /* an unknown intra mode */
+        assert_eq!(
+            decode_symbols(&[0, 4, 0], false),
+            Err(DecodeError::InvalidData)
+        );
+        /* delta-QPs past the largest one */
+        for delta_qp in [MAX_DELTA_QP + 1, -MAX_DELTA_QP - 1, i8::MIN] {
+            assert_eq!(
+                decode_symbols(&[delta_qp as u8, 0, 0, 0], true),
+                Err(DecodeError::InvalidData)
+            );
+        }
+    }
 }
@@ -0,0 +1,172 @@
+use crate::dct::{Plane, MIN_TAIL_SIZE};
+use yuv::YUVFrame;
+
+/** coefficients left in a tail after quantization */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TailKind {
+    /** no coefficient at all */
+    #[default]
+    Empty,
+    /** only the DC coefficient */
+    Flat,
+    /** at least one AC coefficient */
+    Textured,
+}
+
+/** tails of a plane collected while decoding, stored per smallest tail size */
+pub struct TailMap {
+    pub plane: Plane,
+    cols: usize,
+    rows: usize,
+    /**
+     * index of the tail covering each unit, its kind and its DC quantizer step,
+     * the filter gets stronger as the step grows
+     */
+    units: Vec<(usize, TailKind, i32)>,
+    count: usize,
+}
+
+impl TailMap {
+    pub fn new(plane: Plane, width: usize, height: usize) -> Self {
+        let cols = width.div_ceil(MIN_TAIL_SIZE);
+        let rows = height.div_ceil(MIN_TAIL_SIZE);
+        Self {
+            plane,
+            cols,
+            rows,
+            units: vec![(0, TailKind::default(), 0); cols * rows],
+            count: 0,
+        }
+    }
+    /** kind and step of the tail covering pixel (`x`, `y`) along with an index telling tails apart */
+    pub fn get(&self, x: usize, y: usize) -> (usize, TailKind, i32) {
+        self.units[(y / MIN_TAIL_SIZE) * self.cols + x / MIN_TAIL_SIZE]
+    }
+    /** record a `n`x`n` tail at (`x`, `y`) quantized with DC step `q` */
+    pub fn set(&mut self, x: usize, y: usize, n: usize, kind: TailKind, q: i32) {
+        self.count += 1;
+        let (i, j) = (x / MIN_TAIL_SIZE, y / MIN_TAIL_SIZE);
+        let units = n / MIN_TAIL_SIZE;
+        for j in j..(j + units).min(self.rows) {
+            for i in i..(i + units).min(self.cols) {
+                self.units[j * self.cols + i] = (self.count, kind, q);
+            }
+        }
+    }
+}
+
+/**
+ * Boundary strength of the edge between two tails.
+ * Two flat tails get the strong filter since the staircase between them is the most visible,
+ * edges next to textured tails only get a clipped correction, edges between empty tails are skipped.
+*/
+fn boundary_strength(p: TailKind, q: TailKind) -> u8 {
+    match (p, q) {
+        (TailKind::Empty, TailKind::Empty) => 0,
+        (TailKind::Flat, TailKind::Flat) => 2,
+        _ => 1,
+    }
+}
+
+/** filter the samples `p3 p2 p1 p0 | q0 q1 q2 q3` across one edge, like H.264 does */
+fn filter_line(s: &mut [i32; 8], bs: u8, q: i32) {
+    let alpha = q.min(255);
+    let beta = (q / 4 + 1).min(18);
+    let tc0 = q / 8;
+    let [p3, p2, p1, p0, q0, q1, q2, q3] = *s;
+    if (p0 - q0).abs() >= alpha || (p1 - p0).abs() >= beta || (q1 - q0).abs() >= beta {
+        return;
+    }
+    let ap = (p2 - p0).abs() < beta;
+    let aq = (q2 - q0).abs() < beta;
+    if bs == 2 {
+        let smooth = (p0 - q0).abs() < (alpha >> 2) + 2;
+        if smooth && ap {
+            s[3] = (p2 + 2 * p1 + 2 * p0 + 2 * q0 + q1 + 4) >> 3;
+            s[2] = (p2 + p1 + p0 + q0 + 2) >> 2;
+            s[1] = (2 * p3 + 3 * p2 + p1 + p0 + q0 + 4) >> 3;
+        } else {
+            s[3] = (2 * p1 + p0 + q1 + 2) >> 2;
+        }
+        if smooth && aq {
+            s[4] = (p1 + 2 * p0 + 2 * q0 + 2 * q1 + q2 + 4) >> 3;
+            s[5] = (p0 + q0 + q1 + q2 + 2) >> 2;
+            s[6] = (2 * q3 + 3 * q2 + q1 + q0 + p0 + 4) >> 3;
+        } else {
+            s[4] = (2 * q1 + q0 + p1 + 2) >> 2;
+        }
+    } else {
+        let tc = tc0 + ap as i32 + aq as i32;
+        let delta = (((q0 - p0) * 4 + (p1 - q1) + 4) >> 3).clamp(-tc, tc);
+        s[3] = (p0 + delta).clamp(0, 255);
+        s[4] = (q0 - delta).clamp(0, 255);
+        if ap {
+            s[2] = p1 + ((p2 + ((p0 + q0 + 1) >> 1) - 2 * p1) >> 1).clamp(-tc0, tc0);
+        }
+        if aq {
+            s[5] = q1 + ((q2 + ((p0 + q0 + 1) >> 1) - 2 * q1) >> 1).clamp(-tc0, tc0);
+        }
+    }
+}
+
+/** filter the pixels across an edge, `pos(k)` gives the position of sample `k` or `None` outside of the plane */
+fn filter_edge<T, F>(frame: &mut T, plane: Plane, bs: u8, q: i32, pos: F)
+where
+    T: YUVFrame,
+    F: Fn(usize) -> Option<(usize, usize)>,
+{
+    let mut s = [0; 8];
+    let mut last = (0, 0);
+    for (k, e) in s.iter_mut().enumerate() {
+        /* samples outside of the plane repeat the edge pixel */
+        if let Some(p) = pos(k) {
+            last = p;
+        }
+        *e = plane.get_pixel(frame, last.0, last.1) as i32;
+    }
+    filter_line(&mut s, bs, q);
+    for (k, e) in s.iter().enumerate() {
+        if let Some((x, y)) = pos(k) {
+            plane.set_pixel(frame, x, y, *e as u8);
+        }
+    }
+}
+
+/** apply the deblocking filter on every tail edge, vertical edges first */
+pub fn deblock_frame<T: YUVFrame>(frame: &mut T, maps: &[TailMap]) {
+    for map in maps {
+        let plane = map.plane;
+        let (width, height) = plane.resolution(frame);
+        for x in (MIN_TAIL_SIZE..width).step_by(MIN_TAIL_SIZE) {
+            for y in 0..height {
+                let (p, q) = (map.get(x - 1, y), map.get(x, y));
+                if p.0 == q.0 {
+                    continue;
+                }
+                let bs = boundary_strength(p.1, q.1);
+                if bs > 0 {
+                    /* tails on both sides may use different steps, filter with their average */
+                    let step = (p.2 + q.2 + 1) / 2;
+                    filter_edge(frame, plane, bs, step, |k| {
+                        Some((x + k - 4, y)).filter(|p| p.0 < width)
+                    });
+                }
+            }
+        }
+        for y in (MIN_TAIL_SIZE..height).step_by(MIN_TAIL_SIZE) {
+            for x in 0..width {
+                let (p, q) = (map.get(x, y - 1), map.get(x, y));
+                if p.0 == q.0 {
+                    continue;
+                }
+                let bs = boundary_strength(p.1, q.1);
+                if bs > 0 {
+                    let step = (p.2 + q.2 + 1) / 2;
+                    filter_edge(frame, plane, bs, step, |k| {
+                        Some((x, y + k - 4)).filter(|p| p.1 < height)
+                    });
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use crate::bitstream::{BitStreamReader, BitStreamWriter};
+use crate::error::DecodeError;
+use crate::{cabac, huffman, rans};
+
+/** counts of the symbols written so far, what the encoder knows about the code before building it */
+pub struct SymbolStats {
+    freq: [usize; 256],
+    total: usize,
+}
+
+impl Default for SymbolStats {
+    fn default() -> Self {
+        Self {
+            freq: [0; 256],
+            total: 0,
+        }
+    }
+}
+
+impl SymbolStats {
+    pub fn add(&mut self, symbol: u8) {
+        self.freq[symbol as usize] += 1;
+        self.total += 1;
+    }
+    /** information content of `symbol` in bits */
+    pub fn information(&self, symbol: u8) -> f64 {
+        /* every symbol starts with a count of one so that unseen ones still have a cost */
+        let count = self.freq[symbol as usize] as f64 + 1.;
+        ((self.total + 256) as f64 / count).log2()
+    }
+}
+
+/**
+ * Lossless coder of the symbol bytes produced by the coefficient layer.
+ * The coded symbols must be self-delimiting, since other data follows them in the same stream.
+*/
+pub trait EntropyCoder {
+    fn encode(&self, symbols: &[u8], bits: &mut BitStreamWriter);
+    /**
+     * read back the symbols written by `encode`, leaving `bits` right after them,
+     * streams of more than `max_size` symbols are rejected before any is decoded
+     */
+    fn decode(&self, bits: &mut BitStreamReader, max_size: usize) -> Result<Vec<u8>, DecodeError>;
+    /** estimated bits of writing `symbol` next, used by the encoder to compare coding choices */
+    fn estimate_bits(&self, stats: &SymbolStats, symbol: u8) -> f64;
+}
+
+/** canonical Huffman code built for every picture */
+pub struct Huffman;
+
+impl EntropyCoder for Huffman {
+    fn encode(&self, symbols: &[u8], bits: &mut BitStreamWriter) {
+        huffman::encode_into(symbols, huffman::DEFAULT_MAX_CODE_LENGTH, bits);
+    }
+    fn decode(&self, bits: &mut BitStreamReader, max_size: usize) -> Result<Vec<u8>, DecodeError> {
+        huffman::decode_from(bits, max_size)
+    }
+    fn estimate_bits(&self, stats: &SymbolStats, symbol: u8) -> f64 {
+        /* a code takes at least one bit however likely the symbol is */
+        stats.information(symbol).max(1.)
+    }
+}
+
+/** adaptive binary arithmetic coder, can spend less than a bit on a symbol */
+pub struct Arithmetic;
+
+impl EntropyCoder for Arithmetic {
+    fn encode(&self, symbols: &[u8], bits: &mut BitStreamWriter) {
+        cabac::encode_into(symbols, bits);
+    }
+    fn decode(&self, bits: &mut BitStreamReader, max_size: usize) -> Result<Vec<u8>, DecodeError> {
+        cabac::decode_from(bits, max_size)
+    }
+    fn estimate_bits(&self, stats: &SymbolStats, symbol: u8) -> f64 {
+        stats.information(symbol)
+    }
+}
+
+/** static rANS, fractional bits like arithmetic coding at close to the speed of Huffman tables */
+pub struct Rans;
+
+impl EntropyCoder for Rans {
+    fn encode(&self, symbols: &[u8], bits: &mut BitStreamWriter) {
+        rans::encode_into(symbols, bits);
+    }
+    fn decode(&self, bits: &mut BitStreamReader, max_size: usize) -> Result<Vec<u8>, DecodeError> {
+        rans::decode_from(bits, max_size)
+    }
+    fn estimate_bits(&self, stats: &SymbolStats, symbol: u8) -> f64 {
+        stats.information(symbol)
+    }
+}
+
+/** entropy coder of the symbols, chosen by the encoder configuration and stored in the picture header */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EntropyBackend {
+    #[default]
+    Huffman,
+    Arithmetic,
+    Rans,
+}
+
+impl EntropyBackend {
+    pub fn coder(self) -> &'static dyn EntropyCoder {
+        match self {
+            EntropyBackend::Huffman => &Huffman,
+            EntropyBackend::Arithmetic => &Arithmetic,
+            EntropyBackend::Rans => &Rans,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /** bytes with small values the most common, like the coefficient symbols */
+    pub fn symbols(len: usize) -> Vec<u8> {
+        let mut state = 1u32;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                let r = (state >> 16) % 64;
+                (r * r / 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn truncated() {
+        let symbols = symbols(1000);
+        for backend in [
+            EntropyBackend::Huffman,
+            EntropyBackend::Arithmetic,
+            EntropyBackend::Rans,
+        ] {
+            let mut bits = BitStreamWriter::default();
+            backend.coder().encode(&symbols, &mut bits);
+            for len in 0..bits.data.len() {
+                let mut truncated = BitStreamReader::from_bytes(&bits.data[..len]);
+                assert_eq!(
+                    backend.coder().decode(&mut truncated, symbols.len()),
+                    Err(DecodeError::DataOverrun),
+                    "{backend:?} cut to {len} bytes"
+                );
+            }
+        }
+    }
+}
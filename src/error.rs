@@ -0,0 +1,52 @@
+use std::fmt;
+
+/** reason a picture could not be decoded */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /** the picture ends inside its header */
+    TruncatedHeader,
+    /** the picture does not start with the magic header or its header holds an unknown value */
+    InvalidHeader,
+    /** a code or frequency table that describes no valid code */
+    InvalidTree,
+    /** coded data that cannot appear where it was read */
+    InvalidData,
+    /** the coded data ends before everything is read */
+    DataOverrun,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            DecodeError::TruncatedHeader => "truncated header",
+            DecodeError::InvalidHeader => "invalid header",
+            DecodeError::InvalidTree => "invalid code table",
+            DecodeError::InvalidData => "invalid coded data",
+            DecodeError::DataOverrun => "data overrun",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_are_distinct() {
+        let errors = [
+            DecodeError::TruncatedHeader,
+            DecodeError::InvalidHeader,
+            DecodeError::InvalidTree,
+            DecodeError::InvalidData,
+            DecodeError::DataOverrun,
+        ];
+        let messages: Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+        for (i, message) in messages.iter().enumerate() {
+            assert!(!message.is_empty());
+            assert!(!messages[..i].contains(message), "{message} repeats");
+        }
+    }
+}
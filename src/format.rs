@@ -1,7 +1,21 @@
+use crate::dct::{QuantTables, Transform, TAIL_SIZE};
+use crate::entropy::EntropyBackend;
+use crate::error::DecodeError;
+use crate::mat::Matrix;
+
 const MAGIC_HEADER: [u8; 4] = [0x31, 0x0c, 0x00, b'p'];
-const VERSION: u8 = 1;
+const VERSION: u8 = 2;
+/** bytes of the header before the quant tables */
+const HEADER_SIZE: usize = 17;
+
+pub const PIX_FMT_YUV420P: u8 = 1;
 
-const PIX_FMT_YUV420P: u8 = 1;
+const TRANSFORM_FLOAT: u8 = 0;
+const TRANSFORM_INTEGER: u8 = 1;
+
+const ENTROPY_HUFFMAN: u8 = 0;
+const ENTROPY_ARITHMETIC: u8 = 1;
+const ENTROPY_RANS: u8 = 2;
 
 #[derive(Default)]
 /**
@@ -15,31 +29,119 @@ const PIX_FMT_YUV420P: u8 = 1;
  * |5    |6  |YUV type|
  * |6    |8  |Width|
  * |8    |10 |Height|
- * |10   |   |Encoded data|
+ * |10   |11 |Transform|
+ * |11   |12 |Quality|
+ * |12   |13 |Deblocking (1 if enabled)|
+ * |13   |14 |Delta-QP (1 if every region carries one)|
+ * |14   |15 |Lossless (1 if the transform is bypassed)|
+ * |15   |16 |Entropy coder|
+ * |16   |17 |Quant tables flag|
+ * |17   |145|Quant tables (only if flag is 1)|
+ * |     |   |Encoded data|
  *
  * ## YUV type
  * |Name|Value|
  * |----|-----|
  * |YUV420P|1 |
+ *
+ * ## Width and height
+ * Size of the original picture, the encoded planes are padded to whole tails
+ * by repeating the edge pixels and cropped back to this size when decoding.
+ *
+ * ## Transform
+ * |Name|Value|
+ * |----|-----|
+ * |Float|0   |
+ * |Integer|1 |
+ *
+ * ## Entropy coder
+ * |Name|Value|
+ * |----|-----|
+ * |Huffman|0 |
+ * |Arithmetic|1|
+ * |rANS|2    |
+ *
+ * ## Quant tables
+ * Luma table followed by chroma table, each is 64 bytes in row-major order.
+ * Default JPEG tables are used if the flag is 0.
 */
 pub struct PictureFormat {
     pub pix_fmt: u8,
     pub width: u16,
     pub height: u16,
+    pub transform: Transform,
+    pub quality: u8,
+    pub deblock: bool,
+    pub delta_qp: bool,
+    pub lossless: bool,
+    pub entropy: EntropyBackend,
+    pub q_tables: Option<QuantTables>,
     pub data: Vec<u8>,
 }
 
 impl PictureFormat {
-    pub fn load(bytes: &[u8]) -> Self {
+    pub fn load(bytes: &[u8]) -> Result<Self, DecodeError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(DecodeError::TruncatedHeader);
+        }
+        if bytes[0..4] != MAGIC_HEADER {
+            return Err(DecodeError::InvalidHeader);
+        }
+        if bytes[4] != VERSION || bytes[5] != PIX_FMT_YUV420P {
+            return Err(DecodeError::InvalidHeader);
+        }
+        let flag = |byte: u8| match byte {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError::InvalidHeader),
+        };
         let pix_fmt = bytes[5];
         let width = u16::from_be_bytes(bytes[6..8].try_into().unwrap());
         let height = u16::from_be_bytes(bytes[8..10].try_into().unwrap());
-        Self {
+        let transform = match bytes[10] {
+            TRANSFORM_FLOAT => Transform::Float,
+            TRANSFORM_INTEGER => Transform::Integer,
+            _ => return Err(DecodeError::InvalidHeader),
+        };
+        let quality = bytes[11];
+        if !(1..=100).contains(&quality) {
+            return Err(DecodeError::InvalidHeader);
+        }
+        let deblock = flag(bytes[12])?;
+        let delta_qp = flag(bytes[13])?;
+        let lossless = flag(bytes[14])?;
+        let entropy = match bytes[15] {
+            ENTROPY_HUFFMAN => EntropyBackend::Huffman,
+            ENTROPY_ARITHMETIC => EntropyBackend::Arithmetic,
+            ENTROPY_RANS => EntropyBackend::Rans,
+            _ => return Err(DecodeError::InvalidHeader),
+        };
+        let mut offset = HEADER_SIZE;
+        let q_tables = if flag(bytes[16])? {
+            let size = TAIL_SIZE * TAIL_SIZE;
+            if bytes.len() < offset + 2 * size {
+                return Err(DecodeError::TruncatedHeader);
+            }
+            let luma = Matrix::from(&bytes[offset..offset + size], TAIL_SIZE);
+            let chroma = Matrix::from(&bytes[offset + size..offset + 2 * size], TAIL_SIZE);
+            offset += 2 * size;
+            Some(QuantTables { luma, chroma })
+        } else {
+            None
+        };
+        Ok(Self {
             pix_fmt,
             width,
             height,
-            data: bytes[10..].to_vec(),
-        }
+            transform,
+            quality,
+            deblock,
+            delta_qp,
+            lossless,
+            entropy,
+            q_tables,
+            data: bytes[offset..].to_vec(),
+        })
     }
     pub fn dump(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
@@ -48,8 +150,100 @@ impl PictureFormat {
         bytes.push(PIX_FMT_YUV420P);
         bytes.extend(self.width.to_be_bytes());
         bytes.extend(self.height.to_be_bytes());
+        bytes.push(match self.transform {
+            Transform::Float => TRANSFORM_FLOAT,
+            Transform::Integer => TRANSFORM_INTEGER,
+        });
+        bytes.push(self.quality);
+        bytes.push(self.deblock as u8);
+        bytes.push(self.delta_qp as u8);
+        bytes.push(self.lossless as u8);
+        bytes.push(match self.entropy {
+            EntropyBackend::Huffman => ENTROPY_HUFFMAN,
+            EntropyBackend::Arithmetic => ENTROPY_ARITHMETIC,
+            EntropyBackend::Rans => ENTROPY_RANS,
+        });
+        match &self.q_tables {
+            Some(q_tables) => {
+                bytes.push(1);
+                for q_mat in [&q_tables.luma, &q_tables.chroma] {
+                    for j in 0..q_mat.len() {
+                        for i in 0..q_mat.len() {
+                            bytes.push(q_mat.get(i, j));
+                        }
+                    }
+                }
+            }
+            None => bytes.push(0),
+        }
         bytes.extend(&self.data);
 
         bytes
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> Vec<u8> {
+        PictureFormat {
+            pix_fmt: PIX_FMT_YUV420P,
+            width: 16,
+            height: 16,
+            quality: 50,
+            q_tables: Some(QuantTables::default()),
+            ..Default::default()
+        }
+        .dump()
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let picture = PictureFormat::load(&header()).unwrap();
+        assert_eq!(
+            (picture.width, picture.height, picture.quality),
+            (16, 16, 50)
+        );
+        assert!(picture.q_tables.is_some());
+        assert!(picture.data.is_empty());
+    }
+
+    #[test]
+    fn truncated_header() {
+        let bytes = header();
+        for len in 0..bytes.len() {
+            assert_eq!(
+                PictureFormat::load(&bytes[..len]).err(),
+                Some(DecodeError::TruncatedHeader),
+                "{len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_header_values() {
+        /* magic, version, pix_fmt, transform, quality, flags and entropy coder */
+        for (offset, value) in [
+            (0, 0),
+            (4, VERSION + 1),
+            (5, 0),
+            (10, 2),
+            (11, 0),
+            (11, 101),
+            (12, 2),
+            (13, 2),
+            (14, 0xff),
+            (15, 3),
+            (16, 2),
+        ] {
+            let mut bytes = header();
+            bytes[offset] = value;
+            assert_eq!(
+                PictureFormat::load(&bytes).err(),
+                Some(DecodeError::InvalidHeader),
+                "byte {offset} set to {value}"
+            );
+        }
+    }
+}
@@ -1,172 +1,646 @@
 use crate::bitstream::{BitStreamReader, BitStreamWriter};
-use std::rc::Rc;
+use crate::error::DecodeError;
 
-#[derive(Clone, Default, Debug)]
-struct HuffmanNode {
-    byte: u8,
-    value: usize,
-    left: Option<Rc<HuffmanNode>>,
-    right: Option<Rc<HuffmanNode>>,
+/** longest code length the code length table can store */
+const MAX_CODE_LENGTH: usize = 63;
+/** bits of a code length in the code length table */
+const LENGTH_BITS: usize = 6;
+/** bits of a run of unused bytes in the code length table, storing the run minus one */
+const RUN_BITS: usize = 8;
+/** bits of the ID of the code length table, 0 is a custom table sent along */
+const TABLE_ID_BITS: usize = 8;
+/** bits looked up at once by the first level decoding table */
+const TABLE_BITS: usize = 9;
+/** largest number of bits looked up by a second level table, longer codes are read bit by bit */
+const SUB_TABLE_BITS: usize = 7;
+
+/* statistical the frequencies */
+fn stat_freq(bytes: &[u8]) -> [usize; 256] {
+    let mut freq_list = [0; 256];
+    for byte in bytes {
+        freq_list[*byte as usize] += 1;
+    }
+
+    freq_list
 }
 
-impl HuffmanNode {
-    /* statistical the frequencies */
-    fn stat_freq(bytes: &[u8]) -> [usize; 256] {
-        let mut freq_list = [0; 256];
-        for byte in bytes {
-            freq_list[*byte as usize] += 1;
-        }
+/**
+ * Code length of every byte, 0 for bytes that do not occur.
+ * The Huffman tree is built with two queues, the sorted leaves and the merged nodes
+ * which come out in increasing weight, so it takes linear time after sorting the leaves.
+ * Nodes only record their parent, the depth of a leaf is its code length.
+*/
+fn code_lengths(freq_list: &[usize; 256]) -> [u8; 256] {
+    let mut leaves: Vec<(usize, u8)> = (0..=255)
+        .filter(|b| freq_list[*b as usize] > 0)
+        .map(|b| (freq_list[b as usize], b))
+        .collect();
+    leaves.sort();
 
-        freq_list
+    let mut lengths = [0; 256];
+    let n = leaves.len();
+    if n == 1 {
+        /* a lone byte still needs one bit */
+        lengths[leaves[0].1 as usize] = 1;
     }
-    fn build(freq_list: &[usize]) -> Self {
-        fn find_min(freq_list: &mut Vec<HuffmanNode>) -> HuffmanNode {
-            let mut min_pos = 0;
-            for i in 0..freq_list.len() {
-                if freq_list[i].value < freq_list[min_pos].value {
-                    min_pos = i;
-                }
+    if n < 2 {
+        return lengths;
+    }
+
+    /* leaves are nodes 0..n, merged nodes follow in the order they are made */
+    let mut weights: Vec<usize> = leaves.iter().map(|(freq, _)| *freq).collect();
+    let mut parents = vec![0; 2 * n - 1];
+    let (mut leaf, mut merged) = (0, n);
+    for node in n..2 * n - 1 {
+        let mut children = [0; 2];
+        for child in &mut children {
+            if leaf < n && (merged == node || weights[leaf] <= weights[merged]) {
+                *child = leaf;
+                leaf += 1;
+            } else {
+                *child = merged;
+                merged += 1;
             }
-            let min = freq_list[min_pos].clone();
-            freq_list.remove(min_pos);
-            min
         }
+        weights.push(weights[children[0]] + weights[children[1]]);
+        parents[children[0]] = node;
+        parents[children[1]] = node;
+    }
 
-        let mut freq_node_list = Vec::new();
+    /* parents come after their children, so depths are known going backwards from the root */
+    let mut depths = vec![0u8; 2 * n - 1];
+    for node in (0..2 * n - 2).rev() {
+        depths[node] = depths[parents[node]] + 1;
+    }
+    for (node, (_, byte)) in leaves.iter().enumerate() {
+        lengths[*byte as usize] = depths[node];
+    }
+    lengths
+}
 
-        for (byte, freq) in freq_list.iter().enumerate() {
-            let node = HuffmanNode {
-                byte: byte as u8,
-                value: *freq,
-                ..Default::default()
-            };
-            freq_node_list.push(node);
-        }
+/** number of codes of every length, without unused bytes */
+fn length_counts(lengths: &[u8; 256]) -> [usize; MAX_CODE_LENGTH + 1] {
+    let mut counts = [0; MAX_CODE_LENGTH + 1];
+    for length in lengths {
+        counts[*length as usize] += 1;
+    }
+    counts[0] = 0;
+    counts
+}
+
+/**
+ * Code lengths no longer than `max_length`, which must leave room for all bytes that occur.
+ * Plain Huffman lengths are used when they fit, otherwise package-merge finds the optimal ones:
+ * items of `max_length` levels are made by pairing the cheapest items of the level below
+ * into packages and merging them with the leaves, the cheapest `2n - 2` items of the last level
+ * then give every byte one bit of length for each of them it takes part in.
+*/
+fn limited_code_lengths(freq_list: &[usize; 256], max_length: usize) -> [u8; 256] {
+    let lengths = code_lengths(freq_list);
+    if lengths.iter().all(|l| *l as usize <= max_length) {
+        return lengths;
+    }
 
-        while freq_node_list.len() > 1 {
-            let mut upper = HuffmanNode::default();
-            let min1 = find_min(&mut freq_node_list);
-            let min2 = find_min(&mut freq_node_list);
+    let mut leaves: Vec<(usize, u8)> = (0..=255)
+        .filter(|b| freq_list[*b as usize] > 0)
+        .map(|b| (freq_list[b as usize], b))
+        .collect();
+    leaves.sort();
+    /* every item is its weight and the bytes it is made of */
+    let leaf_items: Vec<(usize, Vec<u8>)> = leaves.iter().map(|(f, b)| (*f, vec![*b])).collect();
+    let mut items = leaf_items.clone();
+    for _ in 1..max_length {
+        let packages = items.chunks_exact(2).map(|pair| {
+            let bytes = [pair[0].1.as_slice(), pair[1].1.as_slice()].concat();
+            (pair[0].0 + pair[1].0, bytes)
+        });
+        let mut merged: Vec<(usize, Vec<u8>)> =
+            leaf_items.iter().cloned().chain(packages).collect();
+        /* stable, so leaves stay ahead of packages of the same weight */
+        merged.sort_by_key(|(weight, _)| *weight);
+        items = merged;
+    }
 
-            upper.left = Some(Rc::new(min1));
-            upper.right = Some(Rc::new(min2));
-            upper.value = upper.left.clone().unwrap().value + upper.right.clone().unwrap().value;
-            freq_node_list.push(upper);
+    let mut lengths = [0; 256];
+    for (_, bytes) in &items[..2 * leaves.len() - 2] {
+        for byte in bytes {
+            lengths[*byte as usize] += 1;
         }
+    }
+    lengths
+}
 
-        freq_node_list.first().unwrap().clone()
+/**
+ * Canonical codes of the given code lengths.
+ * Codes are handed out in order of length then byte, counting up and appending zeros
+ * whenever the length grows, so the lengths alone define the code.
+ * As in DEFLATE, the first code of every length is worked out from the counts beforehand.
+*/
+fn canonical_codes(lengths: &[u8; 256]) -> [u64; 256] {
+    let counts = length_counts(lengths);
+    let mut next = [0; MAX_CODE_LENGTH + 1];
+    for length in 1..=MAX_CODE_LENGTH {
+        next[length] = (next[length - 1] + counts[length - 1] as u64) << 1;
     }
-    /** Get Huffman code by byte */
-    fn get_huffman_code(&self, byte: u8) -> Vec<u8> {
-        fn get_huffman_code(root: &HuffmanNode, byte: u8, bits: &mut Vec<u8>) -> bool {
-            if root.is_leaf() {
-                root.byte == byte
-            } else {
-                if get_huffman_code(&root.left.clone().unwrap(), byte, bits) {
-                    bits.push(0);
-                    return true;
-                }
-                if get_huffman_code(&root.right.clone().unwrap(), byte, bits) {
-                    bits.push(1);
-                    return true;
-                }
-                false
-            }
+    let mut codes = [0; 256];
+    for (byte, length) in lengths.iter().enumerate() {
+        if *length > 0 {
+            codes[byte] = next[*length as usize];
+            next[*length as usize] += 1;
         }
+    }
+    codes
+}
 
-        let mut bits = Vec::new();
-        get_huffman_code(self, byte, &mut bits);
-        bits.reverse();
-        bits
+/** bytes that have a code, ordered by code length then byte */
+fn sorted_bytes(lengths: &[u8; 256]) -> Vec<u8> {
+    let counts = length_counts(lengths);
+    let mut offsets = [0; MAX_CODE_LENGTH + 1];
+    for length in 1..MAX_CODE_LENGTH {
+        offsets[length + 1] = offsets[length] + counts[length];
     }
-    fn get_dict(&self) -> Vec<Vec<u8>> {
-        let mut dict = vec![Vec::new(); 256];
-        for (i, byte) in dict.iter_mut().enumerate() {
-            *byte = self.get_huffman_code(i as u8);
+    let mut bytes = vec![0; counts.iter().sum()];
+    for (byte, length) in lengths.iter().enumerate() {
+        if *length > 0 {
+            bytes[offsets[*length as usize]] = byte as u8;
+            offsets[*length as usize] += 1;
         }
+    }
+    bytes
+}
 
-        dict
+/**
+ * Store the code lengths of all bytes.
+ * Every entry is either `1` and the code length of the next byte,
+ * or `0` and a run of up to 256 bytes without code.
+*/
+fn dump_lengths(lengths: &[u8; 256], bits: &mut BitStreamWriter) {
+    let mut byte = 0;
+    while byte < lengths.len() {
+        if lengths[byte] > 0 {
+            bits.write(1);
+            bits.write_bits(lengths[byte] as u32, LENGTH_BITS);
+            byte += 1;
+        } else {
+            let run = lengths[byte..].iter().take_while(|l| **l == 0).count();
+            bits.write(0);
+            bits.write_bits(run as u32 - 1, RUN_BITS);
+            byte += run;
+        }
     }
-    /** Get a byte code by Huffman code */
-    fn get_byte(&self, bits: &mut BitStreamReader) -> u8 {
-        let mut root = Rc::new(self.clone());
-        while !root.is_leaf() {
-            if bits.read() == 0 {
-                root = root.left.clone().unwrap();
-            } else {
-                root = root.right.clone().unwrap();
-            }
+}
+
+/** bits taken by the codes of bytes with these frequencies */
+fn coded_size(freq_list: &[usize; 256], lengths: &[u8; 256]) -> usize {
+    freq_list
+        .iter()
+        .zip(lengths)
+        .map(|(freq, length)| freq * *length as usize)
+        .sum()
+}
+
+/**
+ * ID of the code length table taking the fewest bits for the bytes, along with its code lengths.
+ * A built-in table is only picked when it has a code for every byte and no code longer than `max_length`.
+*/
+fn choose_table(freq_list: &[usize; 256], custom: [u8; 256], max_length: usize) -> (u8, [u8; 256]) {
+    let mut table = BitStreamWriter::default();
+    dump_lengths(&custom, &mut table);
+    let mut best = (0, custom, table.bit_len() + coded_size(freq_list, &custom));
+    for (id, lengths) in STATIC_TABLES.iter().enumerate() {
+        if lengths.iter().any(|l| *l == 0 || *l as usize > max_length) {
+            continue;
         }
-        root.byte
-    }
-    /** load Huffman tree from binary */
-    fn load_from_bits(bits: &mut BitStreamReader, words: &mut Vec<u8>) -> Self {
-        let mut root = Self::default();
-        if bits.read() == 0 {
-            let left = Self::load_from_bits(bits, words);
-            root.left = Some(Rc::new(left));
-            let right = Self::load_from_bits(bits, words);
-            root.right = Some(Rc::new(right));
-            root
-        } else {
-            root.byte = *words.first().unwrap();
-            words.remove(0);
-            root
+        let size = coded_size(freq_list, lengths);
+        if size < best.2 {
+            best = (id as u8 + 1, *lengths, size);
         }
     }
+    (best.0, best.1)
+}
 
-    /** dump Huffman tree into binary */
-    fn dump_to_bits(&self, bits: &mut BitStreamWriter, words: &mut Vec<u8>) {
-        if self.is_leaf() {
-            bits.write(1);
-            words.push(self.byte);
+fn load_table(bits: &mut BitStreamReader) -> Result<[u8; 256], DecodeError> {
+    match bits.read_bits(TABLE_ID_BITS)? as usize {
+        0 => load_lengths(bits),
+        id => STATIC_TABLES
+            .get(id - 1)
+            .copied()
+            .ok_or(DecodeError::InvalidTree),
+    }
+}
+
+/** read code lengths, rejecting those with more codes than the lengths leave room for */
+fn load_lengths(bits: &mut BitStreamReader) -> Result<[u8; 256], DecodeError> {
+    let mut lengths = [0; 256];
+    let mut byte = 0;
+    while byte < lengths.len() {
+        if bits.read()? == 1 {
+            lengths[byte] = bits.read_bits(LENGTH_BITS)? as u8;
+            byte += 1;
         } else {
-            bits.write(0);
-            self.left.clone().unwrap().dump_to_bits(bits, words);
-            self.right.clone().unwrap().dump_to_bits(bits, words);
+            byte += bits.read_bits(RUN_BITS)? as usize + 1;
+        }
+    }
+
+    /* codes left at each length, doubling with every extra bit */
+    let mut left = 1i128;
+    for count in &length_counts(&lengths)[1..] {
+        left = left * 2 - *count as i128;
+        if left < 0 {
+            return Err(DecodeError::InvalidTree);
+        }
+    }
+    Ok(lengths)
+}
+
+/**
+ * Built-in code lengths of every byte, referenced by `table ID - 1` instead of sending a table.
+ * They were trained on the symbols of the coefficient layer, every byte has a code of at most 16 bits.
+ * A row holds the symbols of one run of zeros, by the size of the level.
+*/
+#[rustfmt::skip]
+const STATIC_TABLES: [[u8; 256]; 3] = [
+    /* transform coded pictures at low quality */
+    [
+        2, 2, 3, 3, 5, 7, 9, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 4, 6, 7, 10, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 5, 7, 11, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 5, 9, 14, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 6, 10, 15, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 7, 11, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 7, 13, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 9, 15, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 9, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 9, 14, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 9, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 10, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 12, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 11, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 10, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        9, 11, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+    ],
+    /* transform coded pictures at high quality */
+    [
+        3, 2, 2, 3, 4, 6, 7, 8, 10, 11, 14, 16, 16, 16, 16, 16,
+        16, 4, 6, 7, 9, 10, 12, 14, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 6, 8, 10, 11, 12, 13, 15, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 6, 9, 11, 13, 14, 15, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 7, 11, 13, 15, 15, 15, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 8, 11, 12, 14, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 8, 11, 13, 14, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 9, 14, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 9, 15, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 10, 14, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 10, 14, 14, 14, 14, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 11, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 11, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 12, 13, 15, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 12, 14, 14, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        10, 13, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+    ],
+    /* lossless residuals */
+    [
+        7, 2, 2, 3, 4, 4, 7, 9, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 4, 4, 6, 8, 8, 10, 13, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 5, 7, 9, 12, 10, 11, 15, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 7, 9, 12, 15, 12, 13, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 7, 12, 14, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 8, 12, 15, 16, 16, 15, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 8, 13, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 9, 13, 14, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 9, 15, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 10, 14, 16, 16, 16, 15, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 10, 15, 16, 16, 16, 15, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 11, 13, 15, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 11, 15, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 11, 15, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        16, 12, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+        9, 12, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16,
+    ],
+];
+
+/** decoding tables of a canonical code, the number of codes of every length and the bytes in code order */
+struct CanonicalDecoder {
+    counts: [usize; MAX_CODE_LENGTH + 1],
+    bytes: Vec<u8>,
+}
+
+impl CanonicalDecoder {
+    fn new(lengths: &[u8; 256]) -> Self {
+        Self {
+            counts: length_counts(lengths),
+            bytes: sorted_bytes(lengths),
+        }
+    }
+    /** read one code, the codes of each length are consecutive numbers starting at `first` */
+    fn get_byte(&self, bits: &mut BitStreamReader) -> Result<u8, DecodeError> {
+        let mut code = 0;
+        let mut first = 0;
+        let mut index = 0;
+        for count in &self.counts[1..] {
+            code |= bits.read()? as usize;
+            if code - first < *count {
+                return Ok(self.bytes[index + code - first]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
         }
+        /* the lengths leave some codes unused */
+        Err(DecodeError::InvalidData)
     }
-    fn is_leaf(&self) -> bool {
-        self.left.is_none() && self.right.is_none()
+}
+
+#[derive(Clone, Copy, Default)]
+enum TableEntry {
+    /** byte and length of the code the looked up bits start with */
+    Code(u8, u8),
+    /** offset and bits of the second level table for the codes longer than the first level */
+    Link(usize, usize),
+    /** code too long for the tables or no code at all */
+    #[default]
+    Slow,
+}
+
+/**
+ * Table driven decoder.
+ * The next `TABLE_BITS` bits index the first level table, which holds every shorter code
+ * in all the entries it is a prefix of. Longer codes continue in a second level table
+ * of their prefix, codes too long for that are left to the canonical decoder.
+*/
+struct TableDecoder {
+    table: Vec<TableEntry>,
+    sub_tables: Vec<TableEntry>,
+    canonical: CanonicalDecoder,
+}
+
+impl TableDecoder {
+    fn new(lengths: &[u8; 256]) -> Self {
+        let codes = canonical_codes(lengths);
+        let mut table = vec![TableEntry::default(); 1 << TABLE_BITS];
+        let mut sub_tables = Vec::new();
+
+        /* longest code behind every prefix decides the size of its second level table */
+        let mut sub_lengths = vec![0; 1 << TABLE_BITS];
+        for (code, length) in codes.iter().zip(lengths.iter().map(|l| *l as usize)) {
+            if length > TABLE_BITS {
+                let prefix = (code >> (length - TABLE_BITS)) as usize;
+                sub_lengths[prefix] = sub_lengths[prefix].max(length - TABLE_BITS);
+            }
+        }
+        for (prefix, length) in sub_lengths.into_iter().enumerate() {
+            if length > 0 {
+                let bits = length.min(SUB_TABLE_BITS);
+                table[prefix] = TableEntry::Link(sub_tables.len(), bits);
+                sub_tables.resize(sub_tables.len() + (1 << bits), TableEntry::default());
+            }
+        }
+
+        for (byte, (code, length)) in codes.iter().zip(lengths).enumerate() {
+            let entry = TableEntry::Code(byte as u8, *length);
+            let (code, length) = (*code as usize, *length as usize);
+            if length == 0 {
+                continue;
+            }
+            if length <= TABLE_BITS {
+                let start = code << (TABLE_BITS - length);
+                table[start..start + (1 << (TABLE_BITS - length))].fill(entry);
+                continue;
+            }
+            let TableEntry::Link(offset, bits) = table[code >> (length - TABLE_BITS)] else {
+                unreachable!()
+            };
+            let rest = length - TABLE_BITS;
+            if rest <= bits {
+                let start = offset + ((code & ((1 << rest) - 1)) << (bits - rest));
+                sub_tables[start..start + (1 << (bits - rest))].fill(entry);
+            }
+        }
+
+        Self {
+            table,
+            sub_tables,
+            canonical: CanonicalDecoder::new(lengths),
+        }
+    }
+    fn get_byte(&self, bits: &mut BitStreamReader) -> Result<u8, DecodeError> {
+        let mut entry = self.table[bits.peek_bits(TABLE_BITS) as usize];
+        if let TableEntry::Link(offset, sub_bits) = entry {
+            let index = bits.peek_bits(TABLE_BITS + sub_bits) as usize & ((1 << sub_bits) - 1);
+            entry = self.sub_tables[offset + index];
+        }
+        match entry {
+            TableEntry::Code(byte, length) => {
+                bits.skip(length as usize)?;
+                Ok(byte)
+            }
+            _ => self.canonical.get_byte(bits),
+        }
     }
 }
 
+/** longest code `encode` makes, the decoding tables hold every code up to this length */
+pub const DEFAULT_MAX_CODE_LENGTH: usize = TABLE_BITS + SUB_TABLE_BITS;
+
+/**
+ * Compress bytes with a canonical Huffman code whose codes are at most `DEFAULT_MAX_CODE_LENGTH` bits.
+ * The code comes from a built-in table when that is smaller than sending a table built for the bytes.
+ *
+ * # Data structure
+ * |Start|End|Field|
+ * |-----|---|-----|
+ * |0    |4  |Number of bytes|
+ * |4    |5  |Table ID, 0 for a custom table|
+ * |5    |   |Code lengths (custom table only)|
+ * |     |   |Huffman codes|
+*/
 pub fn encode(bytes: &[u8]) -> Vec<u8> {
-    let root = HuffmanNode::build(&HuffmanNode::stat_freq(bytes));
+    encode_limited(bytes, DEFAULT_MAX_CODE_LENGTH)
+}
+
+/**
+ * Compress bytes with codes of at most `max_length` bits,
+ * raised if needed to give every distinct byte a code, and at most 63.
+*/
+pub fn encode_limited(bytes: &[u8], max_length: usize) -> Vec<u8> {
+    let mut bits = BitStreamWriter::default();
+    encode_into(bytes, max_length, &mut bits);
+    bits.data
+}
 
-    let mut compressed_data_bits = BitStreamWriter::default();
-    let dict = root.get_dict();
+/** append the coded bytes to `bits`, laid out like `encode` does */
+pub fn encode_into(bytes: &[u8], max_length: usize, bits: &mut BitStreamWriter) {
+    let freq_list = stat_freq(bytes);
+    let used = freq_list.iter().filter(|f| **f > 0).count();
+    let max_length = max_length.clamp(
+        (used.next_power_of_two().trailing_zeros() as usize).max(1),
+        MAX_CODE_LENGTH,
+    );
+    let (id, lengths) = choose_table(
+        &freq_list,
+        limited_code_lengths(&freq_list, max_length),
+        max_length,
+    );
+    let codes = canonical_codes(&lengths);
+
+    bits.write_bits(bytes.len() as u32, 32);
+    bits.write_bits(id as u32, TABLE_ID_BITS);
+    if id == 0 {
+        dump_lengths(&lengths, bits);
+    }
     for byte in bytes {
-        for bit in &dict[*byte as usize] {
-            compressed_data_bits.write(*bit);
+        let code = codes[*byte as usize];
+        let length = lengths[*byte as usize] as usize;
+        if length <= 32 {
+            bits.write_bits(code as u32, length);
+        } else {
+            bits.write_bits((code >> 32) as u32, length - 32);
+            bits.write_bits(code as u32, 32);
         }
     }
+}
+
+/** decode a stream of any size up to the 32-bit limit of its count */
+pub fn decode(bytes: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    decode_from(&mut BitStreamReader::from_bytes(bytes), usize::MAX)
+}
+
+/** read at most `max_size` bytes written by `encode_into`, leaving `bits` right after them */
+pub fn decode_from(bits: &mut BitStreamReader, max_size: usize) -> Result<Vec<u8>, DecodeError> {
+    let size = bits.read_bits(32)? as usize;
+    if size > max_size {
+        return Err(DecodeError::DataOverrun);
+    }
+    let decoder = TableDecoder::new(&load_table(bits)?);
+    /* every code takes at least a bit */
+    if size > bits.remaining() {
+        return Err(DecodeError::DataOverrun);
+    }
+
+    let mut data = Vec::with_capacity(size);
+    for _ in 0..size {
+        data.push(decoder.get_byte(bits)?);
+    }
 
-    let mut data = Vec::new();
-    let mut huffman_table = BitStreamWriter::default();
-    let mut words = Vec::new();
-    root.dump_to_bits(&mut huffman_table, &mut words);
+    Ok(data)
+}
+
+/** reference decoder reading codes one bit at a time, `decode` must give the same bytes */
+pub fn decode_bitwise(bytes: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let mut bits = BitStreamReader::from_bytes(bytes);
+    let size = bits.read_bits(32)? as usize;
+    let decoder = CanonicalDecoder::new(&load_table(&mut bits)?);
+    if size > bits.remaining() {
+        return Err(DecodeError::DataOverrun);
+    }
+
+    let mut data = Vec::with_capacity(size);
+    for _ in 0..size {
+        data.push(decoder.get_byte(&mut bits)?);
+    }
 
-    data.extend((compressed_data_bits.total_bits() as u32).to_be_bytes()); // store bits count
-    data.extend(words);
-    data.extend(huffman_table.data); // store huffman tree
-    data.extend(compressed_data_bits.data);
-    data
+    Ok(data)
 }
 
-pub fn decode(bytes: &[u8]) -> Vec<u8> {
-    let size = u32::from_be_bytes(bytes[0..4].try_into().unwrap()) as usize;
-    let mut bitts = BitStreamReader::from_bytes(&bytes[4 + 256..]);
-    let mut words = bytes[4..4 + 256].to_vec();
-    let huffman_table = HuffmanNode::load_from_bits(&mut bitts, &mut words);
-    let compressed_data = &bytes[4 + 256 + bitts.total_bytes()..];
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entropy::tests::symbols;
 
-    let mut compressed_data_bits = BitStreamReader::from_bytes(compressed_data);
-    let mut data = Vec::new();
-    while compressed_data_bits.total_bits() < size {
-        let byte = huffman_table.get_byte(&mut compressed_data_bits);
-        data.push(byte);
+    #[test]
+    fn round_trip() {
+        for len in [0, 1, 2, 100, 5000] {
+            let bytes = symbols(len);
+            let encoded = encode(&bytes);
+            assert_eq!(decode(&encoded), Ok(bytes.clone()));
+            assert_eq!(decode_bitwise(&encoded), Ok(bytes));
+        }
+        /* a single byte value still takes a one bit code */
+        let bytes = vec![7; 100];
+        assert_eq!(decode(&encode(&bytes)), Ok(bytes));
     }
 
-    data
+    #[test]
+    fn limited_lengths() {
+        /* Fibonacci frequencies give the deepest possible tree, one level per byte */
+        let mut freq_list = [0; 256];
+        let (mut a, mut b) = (1, 1);
+        for freq in &mut freq_list[..24] {
+            *freq = a;
+            (a, b) = (b, a + b);
+        }
+        assert!(code_lengths(&freq_list).iter().any(|l| *l > 16));
+        let bytes: Vec<u8> = (0..24)
+            .flat_map(|byte| std::iter::repeat_n(byte as u8, freq_list[byte]))
+            .collect();
+
+        /* 24 bytes in use need at least 5 bits */
+        for max_length in [5, 8, 16] {
+            let lengths = limited_code_lengths(&freq_list, max_length);
+            let mut kraft = 0;
+            for (freq, length) in freq_list.iter().zip(lengths) {
+                assert_eq!(*freq > 0, length > 0);
+                assert!(
+                    length as usize <= max_length,
+                    "{length} bits over {max_length}"
+                );
+                if length > 0 {
+                    kraft += 1 << (max_length - length as usize);
+                }
+            }
+            assert!(
+                kraft <= 1 << max_length,
+                "lengths over-subscribed at {max_length}"
+            );
+
+            let encoded = encode_limited(&bytes, max_length);
+            assert_eq!(decode(&encoded).as_ref(), Ok(&bytes));
+            assert_eq!(decode_bitwise(&encoded), Ok(bytes.clone()));
+        }
+    }
+
+    #[test]
+    fn over_subscribed_lengths() {
+        /* three codes of one bit */
+        let mut bits = BitStreamWriter::default();
+        bits.write_bits(1, 32);
+        bits.write_bits(0, TABLE_ID_BITS);
+        for _ in 0..3 {
+            bits.write(1);
+            bits.write_bits(1, LENGTH_BITS);
+        }
+        bits.write(0);
+        bits.write_bits(252, RUN_BITS);
+        bits.write_bits(0, 8);
+        assert_eq!(decode(&bits.data), Err(DecodeError::InvalidTree));
+        assert_eq!(decode_bitwise(&bits.data), Err(DecodeError::InvalidTree));
+    }
+
+    #[test]
+    fn table_choice() {
+        /* a few run/size symbols are cheaper to code with a built-in table than to send one */
+        let small = [0x01, 0x02, 0x00, 0x11, 0x01, 0x03, 0x00, 0x21, 0x02, 0x00];
+        let (id, lengths) = choose_table(&stat_freq(&small), code_lengths(&stat_freq(&small)), 16);
+        assert!(id > 0);
+        assert_eq!(lengths, STATIC_TABLES[id as usize - 1]);
+        let encoded = encode(&small);
+        assert_eq!(encoded[4], id);
+        assert_eq!(decode(&encoded), Ok(small.to_vec()));
+        assert_eq!(decode_bitwise(&encoded), Ok(small.to_vec()));
+
+        /* a byte the built-in tables give a long code is cheaper with its own table */
+        let large = vec![0xff; 10000];
+        let encoded = encode(&large);
+        assert_eq!(encoded[4], 0);
+        assert_eq!(decode(&encoded), Ok(large));
+    }
+
+    #[test]
+    fn unknown_table_id() {
+        let mut bits = BitStreamWriter::default();
+        bits.write_bits(1, 32);
+        bits.write_bits(STATIC_TABLES.len() as u32 + 1, TABLE_ID_BITS);
+        bits.write_bits(0, 16);
+        assert_eq!(decode(&bits.data), Err(DecodeError::InvalidTree));
+    }
 }
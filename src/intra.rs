@@ -0,0 +1,129 @@
+use crate::dct::Plane;
+use crate::error::DecodeError;
+use crate::mat::Matrix;
+use yuv::YUVFrame;
+
+/** prediction of a tail from the decoded pixels above and left of it */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IntraMode {
+    /** average of the neighbouring pixels */
+    #[default]
+    Dc,
+    /** repeat the column on the left */
+    Horizontal,
+    /** repeat the row above */
+    Vertical,
+    /** blend of the row above and the column on the left, for gradients */
+    Planar,
+}
+
+pub const INTRA_MODES: [IntraMode; 4] = [
+    IntraMode::Dc,
+    IntraMode::Horizontal,
+    IntraMode::Vertical,
+    IntraMode::Planar,
+];
+
+impl IntraMode {
+    /** mode coded as `symbol` */
+    pub fn from_symbol(symbol: u8) -> Result<Self, DecodeError> {
+        INTRA_MODES
+            .get(symbol as usize)
+            .copied()
+            .ok_or(DecodeError::InvalidData)
+    }
+    pub fn symbol(self) -> u8 {
+        self as u8
+    }
+}
+
+/**
+ * Decoded row above and column on the left of the `n`x`n` tail at (`x`, `y`).
+ * Pixels past the plane repeat the edge pixel, a missing side is filled from the other one
+ * and a tail without any neighbour is predicted from mid grey.
+*/
+fn neighbours<T: YUVFrame>(
+    frame: &T,
+    plane: Plane,
+    x: usize,
+    y: usize,
+    n: usize,
+) -> (Vec<i32>, Vec<i32>) {
+    let (width, height) = plane.resolution(frame);
+    let top = (y > 0).then(|| {
+        (0..n)
+            .map(|i| plane.get_pixel(frame, (x + i).min(width - 1), y - 1) as i32)
+            .collect::<Vec<_>>()
+    });
+    let left = (x > 0).then(|| {
+        (0..n)
+            .map(|j| plane.get_pixel(frame, x - 1, (y + j).min(height - 1)) as i32)
+            .collect::<Vec<_>>()
+    });
+    match (top, left) {
+        (Some(top), Some(left)) => (top, left),
+        (Some(top), None) => (top.clone(), vec![top[0]; n]),
+        (None, Some(left)) => (vec![left[0]; n], left),
+        (None, None) => (vec![128; n], vec![128; n]),
+    }
+}
+
+/** predict the `n`x`n` tail at (`x`, `y`) of a plane from the pixels already decoded into `frame` */
+pub fn predict<T: YUVFrame>(
+    frame: &T,
+    plane: Plane,
+    x: usize,
+    y: usize,
+    n: usize,
+    mode: IntraMode,
+) -> Matrix<i32> {
+    let (top, left) = neighbours(frame, plane, x, y, n);
+    let size = n as i32;
+    let dc = (top.iter().sum::<i32>() + left.iter().sum::<i32>() + size) / (2 * size);
+    let mut pred = Matrix::new(n);
+    for i in 0..n {
+        for j in 0..n {
+            let value = match mode {
+                IntraMode::Dc => dc,
+                IntraMode::Horizontal => left[j],
+                IntraMode::Vertical => top[i],
+                IntraMode::Planar => {
+                    /* like HEVC, with the last pixels of the row and column standing in for the corners */
+                    let (u, v) = (i as i32, j as i32);
+                    ((size - 1 - u) * left[j]
+                        + (u + 1) * top[n - 1]
+                        + (size - 1 - v) * top[i]
+                        + (v + 1) * left[n - 1]
+                        + size)
+                        / (2 * size)
+                }
+            };
+            pred.set(i, j, value);
+        }
+    }
+    pred
+}
+
+/** mode predicting `tail` with the smallest sum of absolute differences, along with its prediction */
+pub fn choose_mode<T: YUVFrame>(
+    frame: &T,
+    plane: Plane,
+    x: usize,
+    y: usize,
+    tail: &Matrix<u8>,
+) -> (IntraMode, Matrix<i32>) {
+    let n = tail.len();
+    INTRA_MODES
+        .into_iter()
+        .map(|mode| (mode, predict(frame, plane, x, y, n, mode)))
+        .min_by_key(|(_, pred)| {
+            let mut sad = 0;
+            for i in 0..n {
+                for j in 0..n {
+                    sad += (tail.get(i, j) as i32 - pred.get(i, j)).abs();
+                }
+            }
+            sad
+        })
+        .unwrap()
+}
@@ -1,26 +1,319 @@
+pub mod bitstream;
+pub mod cabac;
+pub mod coeff;
 pub mod dct;
+pub mod deblock;
+pub mod entropy;
+pub mod error;
 pub mod format;
 pub mod frames;
 pub mod huffman;
+pub mod intra;
+pub mod lossless;
 pub mod mat;
+pub mod rans;
 
-mod bitstream;
+use coeff::{CoeffReader, CoeffWriter};
+use dct::{DeltaQps, QuantTables, Quantizer, RoiMap, Transform};
+use entropy::EntropyBackend;
+use error::DecodeError;
+use format::PictureFormat;
+use yuv::{YUV420Frame, YUVFrame};
 
-use yuv::{Bitstream, YUV420Frame, YUVFrame};
+#[derive(Clone)]
+pub struct EncoderConfig {
+    pub transform: Transform,
+    /** 1 (smallest) to 100 (best), 50 keeps the standard quantization tables */
+    pub quality: u8,
+    /** custom 8x8 luma and chroma tables, scaled by `quality` like the default ones */
+    pub q_tables: Option<QuantTables>,
+    /** smooth tail edges of the decoded picture */
+    pub deblock: bool,
+    pub quantizer: Quantizer,
+    /**
+     * strength of adaptive quantization, the QP of a region moves this much
+     * for every doubling of its variance, 0 disables it
+     */
+    pub aq_strength: f64,
+    /** QP offsets given by the caller, added on top of adaptive quantization */
+    pub roi: Option<RoiMap>,
+    /** bit-exact coding, every quantization and filtering option is ignored */
+    pub lossless: bool,
+    /** backend coding the symbols, rate decisions estimate bits with the same backend */
+    pub entropy: EntropyBackend,
+}
 
-pub fn encode_frame<T>(src: &T) -> Vec<u8>
-where
-    T: YUVFrame + Bitstream + Clone,
-{
-    let mut src = src.clone();
-    dct::decode_frame(&mut src);
-    huffman::encode(&src.dump())
+impl Default for EncoderConfig {
+    fn default() -> Self {
+        Self {
+            transform: Transform::default(),
+            quality: 50,
+            q_tables: None,
+            deblock: true,
+            quantizer: Quantizer::default(),
+            aq_strength: 0.,
+            roi: None,
+            lossless: false,
+            entropy: EntropyBackend::default(),
+        }
+    }
 }
 
-pub fn decode_frame<T>(src: &[u8], width: usize, height: usize) -> YUV420Frame
+/**
+ * Encode `src` into a picture.
+ * Panics if `src` is wider or higher than the 65535 pixels a picture header can store.
+*/
+pub fn encode_frame<T>(src: &T, config: &EncoderConfig) -> Vec<u8>
 where
-    T: YUVFrame + Bitstream + Clone,
+    T: YUVFrame,
 {
-    let data = huffman::decode(src);
-    YUV420Frame::load(&huffman::decode(&data), width, height)
+    let (width, height) = src.get_resolution();
+    assert!(
+        width <= u16::MAX as usize && height <= u16::MAX as usize,
+        "{width}x{height} frame is larger than a picture can store"
+    );
+    let quality = config.quality.clamp(1, 100);
+    let mut writer = CoeffWriter::new(config.entropy);
+    let mut delta_qps = None;
+    if config.lossless {
+        lossless::encode_frame(src, &mut writer);
+    } else {
+        let q_tables = config.q_tables.clone().unwrap_or_default();
+        delta_qps =
+            (config.aq_strength > 0.).then(|| DeltaQps::from_activity(src, config.aq_strength));
+        if let Some(roi) = &config.roi {
+            delta_qps
+                .get_or_insert_with(|| DeltaQps::new(src))
+                .add_roi(roi);
+        }
+        dct::encode_frame(
+            src,
+            &mut writer,
+            config.transform,
+            &q_tables,
+            quality,
+            config.quantizer,
+            delta_qps.as_ref(),
+        );
+    }
+    PictureFormat {
+        pix_fmt: format::PIX_FMT_YUV420P,
+        width: width as u16,
+        height: height as u16,
+        transform: config.transform,
+        quality,
+        deblock: config.deblock && !config.lossless,
+        delta_qp: delta_qps.is_some(),
+        lossless: config.lossless,
+        entropy: config.entropy,
+        q_tables: config.q_tables.clone().filter(|_| !config.lossless),
+        data: writer.finish(),
+    }
+    .dump()
+}
+
+/** decode a picture made by `encode_frame`, corrupt or truncated pictures give an error */
+pub fn decode_frame(src: &[u8]) -> Result<YUV420Frame, DecodeError> {
+    let picture = PictureFormat::load(src)?;
+    let (width, height) = (picture.width as usize, picture.height as usize);
+    let max_symbols = if picture.lossless {
+        lossless::max_symbols(width, height)
+    } else {
+        dct::max_symbols(width, height)
+    };
+    let mut reader = CoeffReader::new(&picture.data, picture.entropy, max_symbols)?;
+    /* a size the data cannot fill is rejected before the frame is allocated */
+    if reader.symbols_left() < dct::region_count(width, height) {
+        return Err(DecodeError::DataOverrun);
+    }
+    let mut frame = YUV420Frame::new(width, height);
+    if picture.lossless {
+        lossless::decode_frame(&mut frame, &mut reader)?;
+        return Ok(frame);
+    }
+    let q_tables = picture.q_tables.unwrap_or_default();
+    let maps = dct::decode_frame(
+        &mut frame,
+        &mut reader,
+        picture.transform,
+        &q_tables,
+        picture.quality,
+        picture.delta_qp,
+    )?;
+    if picture.deblock {
+        deblock::deblock_frame(&mut frame, &maps);
+    }
+    Ok(frame)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use dct::{Plane, RoiRect};
+
+    /** gradients with noise and hard edges, so residuals of every size occur */
+    pub fn frame(width: usize, height: usize) -> YUV420Frame {
+        let mut state = 1u32;
+        let mut frame = YUV420Frame::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                let noise = (state >> 16) as usize % 24;
+                let edge = if (x / 7 + y / 5) % 3 == 0 { 200 } else { 0 };
+                frame.set_pixel_y(x, y, ((x * 3 + y * 5 + noise + edge) % 256) as u8);
+                frame.set_pixel_u(x, y, ((x * 2 + noise) % 256) as u8);
+                frame.set_pixel_v(x, y, (255 - (y * 2 + noise) % 256) as u8);
+            }
+        }
+        frame
+    }
+
+    /** PSNR of every plane of `decoded` against `src` in dB */
+    fn psnr(src: &YUV420Frame, decoded: &YUV420Frame) -> [f64; 3] {
+        [Plane::Y, Plane::U, Plane::V].map(|plane| {
+            let (width, height) = plane.resolution(src);
+            let mut error = 0.;
+            for y in 0..height {
+                for x in 0..width {
+                    let a = plane.get_pixel(src, x, y) as f64;
+                    let b = plane.get_pixel(decoded, x, y) as f64;
+                    error += (a - b).powi(2);
+                }
+            }
+            let mse = (error / (width * height) as f64).max(1e-3);
+            10. * (255. * 255. / mse).log10()
+        })
+    }
+
+    #[test]
+    fn lossy_round_trip() {
+        let roi = RoiMap::Rects(vec![RoiRect {
+            x: 8,
+            y: 0,
+            width: 40,
+            height: 24,
+            qp_offset: -6,
+        }]);
+        let mut custom = QuantTables::default();
+        custom.luma = custom.luma.convert(|e| e / 2 + 1);
+        let configs = [
+            EncoderConfig::default(),
+            EncoderConfig {
+                deblock: false,
+                ..Default::default()
+            },
+            EncoderConfig {
+                quality: 90,
+                q_tables: Some(custom),
+                ..Default::default()
+            },
+            EncoderConfig {
+                aq_strength: 1.,
+                ..Default::default()
+            },
+            EncoderConfig {
+                roi: Some(roi),
+                ..Default::default()
+            },
+            EncoderConfig {
+                quantizer: Quantizer::dead_zone(),
+                ..Default::default()
+            },
+            EncoderConfig {
+                quantizer: Quantizer::DeadZone {
+                    intra: 1. / 3.,
+                    inter: 1. / 6.,
+                    adaptive: true,
+                },
+                ..Default::default()
+            },
+            EncoderConfig {
+                quantizer: Quantizer::Rdo { lambda: 0.1 },
+                ..Default::default()
+            },
+        ];
+        for transform in [Transform::Float, Transform::Integer] {
+            for config in &configs {
+                let config = EncoderConfig {
+                    transform,
+                    ..config.clone()
+                };
+                for (width, height) in [(1, 1), (33, 17), (70, 45)] {
+                    let src = frame(width, height);
+                    let decoded = decode_frame(&encode_frame(&src, &config)).unwrap();
+                    assert_eq!(decoded.get_resolution(), (width, height));
+                    let floor = if config.quality > 50 { 38. } else { 28. };
+                    for psnr in psnr(&src, &decoded) {
+                        assert!(
+                            psnr > floor,
+                            "{transform:?} {:?} {width}x{height} at {psnr:.1} dB",
+                            config.quantizer
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn size_beyond_data() {
+        for lossless in [false, true] {
+            for entropy in [
+                EntropyBackend::Huffman,
+                EntropyBackend::Arithmetic,
+                EntropyBackend::Rans,
+            ] {
+                let config = EncoderConfig {
+                    lossless,
+                    entropy,
+                    ..Default::default()
+                };
+                let mut bytes = encode_frame(&YUV420Frame::new(1, 1), &config);
+                assert!(decode_frame(&bytes).is_ok());
+                /* the largest size a header can claim */
+                bytes[6..10].fill(0xff);
+                assert_eq!(decode_frame(&bytes).err(), Some(DecodeError::DataOverrun));
+            }
+        }
+    }
+
+    #[test]
+    fn symbols_beyond_size() {
+        /* one byte value holding all 4096 rANS slots codes any number of symbols in no bits */
+        let mut data = rans::encode(&[0]);
+        data[0..4].copy_from_slice(&500_000_000u32.to_be_bytes());
+        let picture = PictureFormat {
+            pix_fmt: format::PIX_FMT_YUV420P,
+            width: 1,
+            height: 1,
+            quality: 50,
+            entropy: EntropyBackend::Rans,
+            data,
+            ..Default::default()
+        };
+        assert_eq!(
+            decode_frame(&picture.dump()).err(),
+            Some(DecodeError::DataOverrun)
+        );
+    }
+
+    #[test]
+    fn largest_width() {
+        let config = EncoderConfig {
+            lossless: true,
+            ..Default::default()
+        };
+        let frame = YUV420Frame::new(u16::MAX as usize, 1);
+        let decoded = decode_frame(&encode_frame(&frame, &config)).unwrap();
+        assert_eq!(decoded.get_resolution(), (u16::MAX as usize, 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "larger than a picture can store")]
+    fn width_beyond_header() {
+        encode_frame(
+            &YUV420Frame::new(u16::MAX as usize + 2, 1),
+            &EncoderConfig::default(),
+        );
+    }
 }
@@ -0,0 +1,116 @@
+use crate::coeff::{CoeffReader, CoeffWriter};
+use crate::dct::{Plane, TAIL_SIZE};
+use crate::error::DecodeError;
+use crate::mat::Matrix;
+use yuv::YUVFrame;
+
+/** median edge detector of JPEG-LS from the left, upper and upper left pixels */
+fn med(a: i32, b: i32, c: i32) -> i32 {
+    if c >= a.max(b) {
+        a.min(b)
+    } else if c <= a.min(b) {
+        a.max(b)
+    } else {
+        a + b - c
+    }
+}
+
+/** prediction of pixel (`x`, `y`) from the pixels before it, which are already decoded */
+fn predict<T: YUVFrame>(frame: &T, plane: Plane, x: usize, y: usize) -> i32 {
+    let pixel = |x, y| plane.get_pixel(frame, x, y) as i32;
+    match (x, y) {
+        (0, 0) => 128,
+        (_, 0) => pixel(x - 1, 0),
+        (0, _) => pixel(0, y - 1),
+        _ => med(pixel(x - 1, y), pixel(x, y - 1), pixel(x - 1, y - 1)),
+    }
+}
+
+/** positions of the pixels of the 8x8 tail at (`x`, `y`) inside a plane, in raster order */
+fn tail_pixels(
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+) -> impl Iterator<Item = (usize, usize)> {
+    (0..TAIL_SIZE)
+        .flat_map(|j| (0..TAIL_SIZE).map(move |i| (i, j)))
+        .filter(move |(i, j)| x + i < width && y + j < height)
+}
+
+/** most symbols a `width`x`height` picture can take, one per sample of every 8x8 tail */
+pub fn max_symbols(width: usize, height: usize) -> usize {
+    let samples = |width: usize, height: usize| {
+        width.div_ceil(TAIL_SIZE) * height.div_ceil(TAIL_SIZE) * TAIL_SIZE * TAIL_SIZE
+    };
+    samples(width, height) + 2 * samples(width.div_ceil(2), height.div_ceil(2))
+}
+
+/**
+ * Code every plane of `src` without loss.
+ * The transform is bypassed, every pixel is predicted from its decoded neighbours and
+ * the prediction error, reduced modulo 256, is coded in 8x8 tails in raster order.
+*/
+pub fn encode_frame<T: YUVFrame>(src: &T, writer: &mut CoeffWriter) {
+    for plane in [Plane::Y, Plane::U, Plane::V] {
+        let (width, height) = plane.resolution(src);
+        for y in (0..height).step_by(TAIL_SIZE) {
+            for x in (0..width).step_by(TAIL_SIZE) {
+                let mut residual = Matrix::new(TAIL_SIZE);
+                for (i, j) in tail_pixels(x, y, width, height) {
+                    let error = plane.get_pixel(src, x + i, y + j) as i32
+                        - predict(src, plane, x + i, y + j);
+                    residual.set(i, j, error as i8 as i16);
+                }
+                writer.write_block(&residual);
+            }
+        }
+    }
+}
+
+pub fn decode_frame<T: YUVFrame>(dst: &mut T, reader: &mut CoeffReader) -> Result<(), DecodeError> {
+    for plane in [Plane::Y, Plane::U, Plane::V] {
+        let (width, height) = plane.resolution(dst);
+        for y in (0..height).step_by(TAIL_SIZE) {
+            for x in (0..width).step_by(TAIL_SIZE) {
+                let residual = reader.read_block(TAIL_SIZE)?;
+                for (i, j) in tail_pixels(x, y, width, height) {
+                    let pixel = predict(dst, plane, x + i, y + j) + residual.get(i, j) as i32;
+                    plane.set_pixel(dst, x + i, y + j, pixel as u8);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entropy::EntropyBackend;
+    use crate::tests::frame;
+    use crate::{decode_frame, encode_frame, EncoderConfig};
+    use yuv::Bitstream;
+
+    #[test]
+    fn round_trip_is_bit_exact() {
+        for entropy in [
+            EntropyBackend::Huffman,
+            EntropyBackend::Arithmetic,
+            EntropyBackend::Rans,
+        ] {
+            for (width, height) in [(1, 1), (8, 8), (33, 17), (64, 48), (101, 7)] {
+                let src = frame(width, height);
+                let config = EncoderConfig {
+                    lossless: true,
+                    entropy,
+                    ..Default::default()
+                };
+                let decoded = decode_frame(&encode_frame(&src, &config)).unwrap();
+                assert!(
+                    decoded.dump() == src.dump(),
+                    "{entropy:?} {width}x{height} is not bit-exact"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use crate::bitstream::{BitStreamReader, BitStreamWriter};
+use crate::error::DecodeError;
+
+/** frequencies are normalized to sum up to `1 << SCALE_BITS` */
+const SCALE_BITS: u32 = 12;
+/** lower bound of a normalized state, states stay in `RANS_L..RANS_L << 8` */
+const RANS_L: u32 = 1 << 23;
+/** number of interleaved states, symbol `i` is coded by state `i % STATES` */
+const STATES: usize = 2;
+/** bits of the bit length of a frequency in the frequency table */
+const FREQ_LENGTH_BITS: usize = 4;
+/** bits of a run of unused bytes in the frequency table, storing the run minus one */
+const RUN_BITS: usize = 8;
+
+/**
+ * Frequencies of the bytes scaled to sum up to `1 << SCALE_BITS`,
+ * every byte that occurs keeps at least 1 and the most frequent ones absorb the rounding.
+*/
+fn normalized_freqs(bytes: &[u8]) -> [u32; 256] {
+    let mut counts = [0u64; 256];
+    for byte in bytes {
+        counts[*byte as usize] += 1;
+    }
+    let mut freqs = [0; 256];
+    if bytes.is_empty() {
+        return freqs;
+    }
+    for (freq, count) in freqs.iter_mut().zip(counts) {
+        if count > 0 {
+            *freq = ((count << SCALE_BITS) / bytes.len() as u64).max(1) as u32;
+        }
+    }
+    let mut sum: u32 = freqs.iter().sum();
+    /* rare bytes rounded up to 1 can overshoot the total, take it back from the most frequent ones */
+    while sum > 1 << SCALE_BITS {
+        let largest = (0..256).max_by_key(|b| freqs[*b]).unwrap();
+        freqs[largest] -= 1;
+        sum -= 1;
+    }
+    let largest = (0..256).max_by_key(|b| freqs[*b]).unwrap();
+    freqs[largest] += (1 << SCALE_BITS) - sum;
+    freqs
+}
+
+/** start of every byte's slot range */
+fn cumulative(freqs: &[u32; 256]) -> [u32; 256] {
+    let mut cum = [0; 256];
+    for byte in 1..256 {
+        cum[byte] = cum[byte - 1] + freqs[byte - 1];
+    }
+    cum
+}
+
+/**
+ * Store the frequencies of all bytes.
+ * Every entry is either `1`, the bit length of the next frequency and its bits below the leading one,
+ * or `0` and a run of up to 256 bytes that do not occur.
+*/
+fn dump_freqs(freqs: &[u32; 256], bits: &mut BitStreamWriter) {
+    let mut byte = 0;
+    while byte < freqs.len() {
+        if freqs[byte] > 0 {
+            let length = 32 - freqs[byte].leading_zeros() as usize;
+            bits.write(1);
+            bits.write_bits(length as u32, FREQ_LENGTH_BITS);
+            bits.write_bits(freqs[byte], length - 1);
+            byte += 1;
+        } else {
+            let run = freqs[byte..].iter().take_while(|f| **f == 0).count();
+            bits.write(0);
+            bits.write_bits(run as u32 - 1, RUN_BITS);
+            byte += run;
+        }
+    }
+}
+
+/**
+ * Read frequencies of `size` bytes, rejecting those that do not sum up to `1 << SCALE_BITS`.
+ * There are no frequencies at all when there are no bytes.
+*/
+fn load_freqs(bits: &mut BitStreamReader, size: usize) -> Result<[u32; 256], DecodeError> {
+    let mut freqs = [0; 256];
+    let mut byte = 0;
+    while byte < freqs.len() {
+        if bits.read()? == 1 {
+            let length = bits.read_bits(FREQ_LENGTH_BITS)? as usize;
+            if length == 0 || length > SCALE_BITS as usize + 1 {
+                return Err(DecodeError::InvalidTree);
+            }
+            freqs[byte] = (1 << (length - 1)) | bits.read_bits(length - 1)?;
+            byte += 1;
+        } else {
+            byte += bits.read_bits(RUN_BITS)? as usize + 1;
+        }
+    }
+    let sum = freqs.iter().sum::<u32>();
+    if sum != 1 << SCALE_BITS && (size > 0 || sum > 0) {
+        return Err(DecodeError::InvalidTree);
+    }
+    Ok(freqs)
+}
+
+/**
+ * Compress bytes with interleaved rANS.
+ * Bytes are encoded backwards so that they decode forwards, the output is reversed at the end
+ * and starts with the final states.
+ *
+ * # Data structure
+ * |Start|End|Field|
+ * |-----|---|-----|
+ * |0    |4  |Number of bytes|
+ * |4    |   |Frequencies|
+ * |     |   |States, 4 bytes each|
+ * |     |   |Renormalization bytes|
+*/
+pub fn encode(bytes: &[u8]) -> Vec<u8> {
+    let mut bits = BitStreamWriter::default();
+    encode_into(bytes, &mut bits);
+    bits.data
+}
+
+/** append the coded bytes to `bits`, laid out like `encode` does */
+pub fn encode_into(bytes: &[u8], bits: &mut BitStreamWriter) {
+    let freqs = normalized_freqs(bytes);
+    let cum = cumulative(&freqs);
+
+    let mut states = [RANS_L; STATES];
+    let mut output = Vec::new();
+    for (i, byte) in bytes.iter().enumerate().rev() {
+        let state = &mut states[i % STATES];
+        let (freq, start) = (freqs[*byte as usize], cum[*byte as usize]);
+        let max = ((RANS_L >> SCALE_BITS) << 8) * freq;
+        while *state >= max {
+            output.push(*state as u8);
+            *state >>= 8;
+        }
+        *state = ((*state / freq) << SCALE_BITS) + *state % freq + start;
+    }
+    for state in states.iter().rev() {
+        output.extend(state.to_le_bytes());
+    }
+    output.reverse();
+
+    bits.write_bits(bytes.len() as u32, 32);
+    dump_freqs(&freqs, bits);
+    for byte in output {
+        bits.write_bits(byte as u32, 8);
+    }
+}
+
+/** decode a stream of any size up to the 32-bit limit of its count */
+pub fn decode(bytes: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    decode_from(&mut BitStreamReader::from_bytes(bytes), usize::MAX)
+}
+
+/** read at most `max_size` bytes written by `encode_into`, leaving `bits` right after them */
+pub fn decode_from(bits: &mut BitStreamReader, max_size: usize) -> Result<Vec<u8>, DecodeError> {
+    let size = bits.read_bits(32)? as usize;
+    if size > max_size {
+        return Err(DecodeError::DataOverrun);
+    }
+    let freqs = load_freqs(bits, size)?;
+    let cum = cumulative(&freqs);
+    let mut slots = vec![0u8; 1 << SCALE_BITS];
+    for byte in 0..256 {
+        let start = cum[byte] as usize;
+        slots[start..start + freqs[byte] as usize].fill(byte as u8);
+    }
+
+    let mut states = [0; STATES];
+    for state in &mut states {
+        *state = bits.read_bits(32)?;
+        if !(RANS_L..RANS_L << 8).contains(state) {
+            return Err(DecodeError::InvalidData);
+        }
+    }
+    /* a byte can cost no bits at all, so only `max_size` bounds the output, not the data left */
+    let mut data = Vec::with_capacity(size.min(bits.remaining()));
+    for i in 0..size {
+        let state = &mut states[i % STATES];
+        let slot = *state & ((1 << SCALE_BITS) - 1);
+        let byte = slots[slot as usize];
+        *state = freqs[byte as usize] * (*state >> SCALE_BITS) + slot - cum[byte as usize];
+        while *state < RANS_L {
+            *state = (*state << 8) | bits.read_bits(8)?;
+        }
+        data.push(byte);
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entropy::tests::symbols;
+
+    /** a stream of `size` bytes of one value whose frequency is `freq`, followed by `states` */
+    fn single_byte(size: u32, freq: u32, states: [u32; STATES]) -> Vec<u8> {
+        let mut bits = BitStreamWriter::default();
+        bits.write_bits(size, 32);
+        let mut freqs = [0; 256];
+        freqs[0] = freq;
+        dump_freqs(&freqs, &mut bits);
+        for state in states {
+            bits.write_bits(state, 32);
+        }
+        bits.data
+    }
+
+    #[test]
+    fn round_trip() {
+        for len in [0, 1, 2, 3, 100, 5000] {
+            let bytes = symbols(len);
+            assert_eq!(decode(&encode(&bytes)), Ok(bytes));
+        }
+        /* one rare byte among many is rounded up to a frequency of 1 */
+        let bytes: Vec<u8> = (0..100000).map(|i| (i % 50000 == 0) as u8).collect();
+        assert_eq!(decode(&encode(&bytes)), Ok(bytes));
+        assert_eq!(
+            decode(&single_byte(1, 1 << SCALE_BITS, [RANS_L; STATES])),
+            Ok(vec![0])
+        );
+    }
+
+    #[test]
+    fn frequencies_off_scale() {
+        for freq in [1, (1 << SCALE_BITS) - 1, 1 << (SCALE_BITS - 1)] {
+            assert_eq!(
+                decode(&single_byte(1, freq, [RANS_L; STATES])),
+                Err(DecodeError::InvalidTree)
+            );
+        }
+        /* a bit length longer than any frequency */
+        let mut bits = BitStreamWriter::default();
+        bits.write_bits(1, 32);
+        bits.write(1);
+        bits.write_bits(SCALE_BITS + 2, FREQ_LENGTH_BITS);
+        bits.write_bits(0, 32);
+        assert_eq!(decode(&bits.data), Err(DecodeError::InvalidTree));
+    }
+
+    #[test]
+    fn states_out_of_range() {
+        for state in [0, RANS_L - 1, RANS_L << 8, u32::MAX] {
+            for i in 0..STATES {
+                let mut states = [RANS_L; STATES];
+                states[i] = state;
+                assert_eq!(
+                    decode(&single_byte(1, 1 << SCALE_BITS, states)),
+                    Err(DecodeError::InvalidData)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn size_beyond_max() {
+        /* a byte holding every slot costs no bits, so nothing but the bound stops the decoder */
+        let bytes = single_byte(u32::MAX, 1 << SCALE_BITS, [RANS_L; STATES]);
+        let mut bits = BitStreamReader::from_bytes(&bytes);
+        assert_eq!(decode_from(&mut bits, 1000), Err(DecodeError::DataOverrun));
+        let bytes = single_byte(1000, 1 << SCALE_BITS, [RANS_L; STATES]);
+        let mut bits = BitStreamReader::from_bytes(&bytes);
+        assert_eq!(decode_from(&mut bits, 999), Err(DecodeError::DataOverrun));
+        let mut bits = BitStreamReader::from_bytes(&bytes);
+        assert_eq!(decode_from(&mut bits, 1000), Ok(vec![0; 1000]));
+    }
+}
@@ -49,9 +49,28 @@ pub struct YUV420Frame {
 }
 
 impl YUV420Frame {
+    /** create a black frame */
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            points: vec![
+                YUV420 {
+                    cb: 128,
+                    cr: 128,
+                    ..Default::default()
+                };
+                width.div_ceil(2) * height.div_ceil(2)
+            ],
+        }
+    }
+    /** Index of the group of 4 yuv pixels containing (`x`, `y`), odd sizes get a half filled last group */
+    fn group_index(&self, x: usize, y: usize) -> usize {
+        (y / 2) * self.width.div_ceil(2) + x / 2
+    }
     /** Get a group of 4 yuv pixels */
     fn get_pixel_group(&self, x: usize, y: usize) -> YUV420 {
-        self.points[(y / 2) * (self.width / 2) + x / 2].clone()
+        self.points[self.group_index(x, y)].clone()
     }
 }
 
@@ -90,21 +109,24 @@ impl YUVFrame for YUV420Frame {
         self.get_pixel_group(x, y).cr
     }
     fn set_pixel_y(&mut self, i: usize, j: usize, y: u8) {
+        let index = self.group_index(i, j);
         if i % 2 == 0 && j % 2 == 0 {
-            self.points[(j / 2) * (self.width / 2) + i / 2].y[0] = y;
+            self.points[index].y[0] = y;
         } else if i % 2 == 1 && j % 2 == 0 {
-            self.points[(j / 2) * (self.width / 2) + i / 2].y[1] = y;
+            self.points[index].y[1] = y;
         } else if i % 2 == 0 && j % 2 == 1 {
-            self.points[(j / 2) * (self.width / 2) + i / 2].y[2] = y;
+            self.points[index].y[2] = y;
         } else {
-            self.points[(j / 2) * (self.width / 2) + i / 2].y[3] = y;
+            self.points[index].y[3] = y;
         };
     }
     fn set_pixel_u(&mut self, i: usize, j: usize, cb: u8) {
-        self.points[(j / 2) * (self.width / 2) + i / 2].cb = cb;
+        let index = self.group_index(i, j);
+        self.points[index].cb = cb;
     }
     fn set_pixel_v(&mut self, i: usize, j: usize, cr: u8) {
-        self.points[(j / 2) * (self.width / 2) + i / 2].cb = cr;
+        let index = self.group_index(i, j);
+        self.points[index].cr = cr;
     }
     fn get_resolution(&self) -> (usize, usize) {
         (self.width, self.height)
@@ -119,48 +141,46 @@ pub trait Bitstream {
     fn dump(&self) -> Vec<u8>;
 }
 
+/**
+ * Planar layout: a `width`x`height` Y plane followed by Cb and Cr planes,
+ * chroma planes are `ceil(width / 2)`x`ceil(height / 2)`.
+*/
 impl Bitstream for YUV420Frame {
     fn load(bytes: &[u8], width: usize, height: usize) -> Self {
-        let w = width / 2;
-        let h = height / 2;
-        let mut pixels: Vec<YUV420> = Vec::new();
-
-        let cb_area = &bytes[4 * w * h..];
-        let cr_area = &bytes[5 * w * h..];
+        let mut frame = Self::new(width, height);
+        let w = width.div_ceil(2);
+        let h = height.div_ceil(2);
+
+        let cb_area = &bytes[width * height..];
+        let cr_area = &bytes[width * height + w * h..];
+        for y in 0..height {
+            for x in 0..width {
+                frame.set_pixel_y(x, y, bytes[y * width + x]);
+            }
+        }
         for y in 0..h {
             for x in 0..w {
-                let mut pixel = YUV420::default();
-                pixel.y[0] = bytes[4 * y * w + 2 * x];
-                pixel.y[1] = bytes[4 * y * w + 2 * x + 1];
-                pixel.y[2] = bytes[4 * y * w + 2 * w + 2 * x];
-                pixel.y[3] = bytes[4 * y * w + 2 * w + 2 * x + 1];
-
-                pixel.cb = cb_area[y * w + x];
-                pixel.cr = cr_area[y * w + x];
-                pixels.push(pixel);
+                frame.set_pixel_u(2 * x, 2 * y, cb_area[y * w + x]);
+                frame.set_pixel_v(2 * x, 2 * y, cr_area[y * w + x]);
             }
         }
-        Self {
-            width,
-            height,
-            points: pixels,
-        }
+        frame
     }
     fn dump(&self) -> Vec<u8> {
-        let w = self.width / 2;
-        let h = self.height / 2;
-        let mut bytes = vec![0; 4 * h * w];
+        let w = self.width.div_ceil(2);
+        let h = self.height.div_ceil(2);
+        let mut bytes = vec![0; self.width * self.height];
         let mut cb_area = vec![0; h * w];
         let mut cr_area = vec![0; h * w];
 
+        for y in 0..self.height {
+            for x in 0..self.width {
+                bytes[y * self.width + x] = self.get_pixel_y(x, y);
+            }
+        }
         for y in 0..h {
             for x in 0..w {
                 let group = self.get_pixel_group(2 * x, 2 * y);
-                bytes[4 * y * w + 2 * x] = group.y[0];
-                bytes[4 * y * w + 2 * x + 1] = group.y[1];
-                bytes[4 * y * w + 2 * w + 2 * x] = group.y[2];
-                bytes[4 * y * w + 2 * w + 2 * x + 1] = group.y[3];
-
                 cb_area[y * w + x] = group.cb;
                 cr_area[y * w + x] = group.cr;
             }